@@ -0,0 +1,332 @@
+//! Finds out how many peers one media worker can take. Plays the controller for an
+//! in-process SFU and adds fake peers a step at a time, each sending an Opus tone over a
+//! `PlainTransport` and hearing the channel mixed, the way a dial-in call does. After
+//! every step it records join latency, CPU, memory and how much of the mix arrived.
+//!
+//!     loadgen [--peers 200] [--step 20] [--channels 4] [--settle 10] [--format csv|json] [--output report.csv]
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use media_worker_sfu::{run_worker, WorkerConfig, WorkerDeathPolicy, WorkerLogLevel};
+use serde_json::{json, Value};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+const CONTROLLER: usize = usize::MAX;
+const FRAME: usize = 960;
+const TONE_FRAMES: usize = 50;
+
+// Linux reports CPU time in clock ticks, which are 100 a second nearly everywhere.
+const TICKS_PER_SECOND: f64 = 100.0;
+
+struct Options {
+    peers: usize,
+    step: usize,
+    channels: usize,
+    settle: Duration,
+    json: bool,
+    output: Option<String>,
+}
+
+impl Options {
+    fn parse() -> Result<Options> {
+        let mut options = Options {
+            peers: 200,
+            step: 20,
+            channels: 4,
+            settle: Duration::from_secs(10),
+            json: false,
+            output: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| anyhow!("{flag} needs a value"))?;
+            match flag.as_str() {
+                "--peers" => options.peers = value.parse().context("invalid --peers")?,
+                "--step" => options.step = value.parse().context("invalid --step")?,
+                "--channels" => options.channels = value.parse().context("invalid --channels")?,
+                "--settle" => options.settle = Duration::from_secs(value.parse().context("invalid --settle")?),
+                "--format" => options.json = match value.as_str() {
+                    "csv" => false,
+                    "json" => true,
+                    _ => bail!("--format is either csv or json"),
+                },
+                "--output" => options.output = Some(value),
+                _ => bail!("unknown flag {flag}"),
+            }
+        }
+        if options.peers == 0 || options.step == 0 || options.channels == 0 {
+            bail!("--peers, --step and --channels must be at least 1");
+        }
+        Ok(options)
+    }
+}
+
+/// One step of the run, with everything measured while the SFU settled.
+struct Row {
+    peers: usize,
+    join_ms_mean: f64,
+    join_ms_max: f64,
+    cpu_percent: f64,
+    worker_cpu_percent: f64,
+    rss_kib: u64,
+    // Out of the 50 a second each peer should receive.
+    packets_per_peer_per_second: f64,
+}
+
+impl Row {
+    fn to_json(&self) -> Value {
+        json!({
+            "peers": self.peers,
+            "joinMsMean": self.join_ms_mean,
+            "joinMsMax": self.join_ms_max,
+            "cpuPercent": self.cpu_percent,
+            "workerCpuPercent": self.worker_cpu_percent,
+            "rssKiB": self.rss_kib,
+            "packetsPerPeerPerSecond": self.packets_per_peer_per_second,
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        format!("{},{:.2},{:.2},{:.1},{:.1},{},{:.1}",
+                self.peers,
+                self.join_ms_mean,
+                self.join_ms_max,
+                self.cpu_percent,
+                self.worker_cpu_percent,
+                self.rss_kib,
+                self.packets_per_peer_per_second)
+    }
+}
+
+const CSV_HEADER: &str = "peers,join_ms_mean,join_ms_max,cpu_percent,worker_cpu_percent,rss_kib,packets_per_peer_per_second";
+
+/// CPU time used by this process so far, in seconds. The SFU runs in here as well.
+fn cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) as f64 / TICKS_PER_SECOND)
+}
+
+fn rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// A second of a 440Hz tone, encoded once and shared by every peer.
+fn tone() -> Result<Arc<Vec<Vec<u8>>>> {
+    let mut encoder = opus::Encoder::new(48000, opus::Channels::Mono, opus::Application::Voip)?;
+    let mut frames = vec![];
+    let mut encoded = vec![0; 4000];
+    for frame in 0..TONE_FRAMES {
+        let pcm: Vec<i16> = (0..FRAME).map(|i| {
+            let t = (frame * FRAME + i) as f64 / 48000.0;
+            ((t * 440.0 * std::f64::consts::TAU).sin() * 8000.0) as i16
+        }).collect();
+        let n = encoder.encode(&pcm, &mut encoded)?;
+        frames.push(encoded[..n].to_vec());
+    }
+    Ok(Arc::new(frames))
+}
+
+/// Sends the tone to the SFU and counts what comes back, until the task is dropped.
+async fn fake_peer(socket: UdpSocket, sfu: SocketAddr, ssrc: u32, tone: Arc<Vec<Vec<u8>>>, received: Arc<AtomicU64>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    let mut buffer = [0; 1500];
+    let mut sequence: u16 = 0;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut packet = vec![0x80, 111];
+                packet.extend_from_slice(&sequence.to_be_bytes());
+                packet.extend_from_slice(&(sequence as u32 * FRAME as u32).to_be_bytes());
+                packet.extend_from_slice(&ssrc.to_be_bytes());
+                packet.extend_from_slice(&tone[sequence as usize % tone.len()]);
+                _ = socket.send_to(&packet, sfu).await;
+                sequence = sequence.wrapping_add(1);
+            }
+            result = socket.recv_from(&mut buffer) => {
+                if result.is_ok() {
+                    received.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+struct Controller {
+    ws: futures_util::stream::SplitSink<tokio_tungstenite::WebSocketStream<tokio::io::DuplexStream>, Message>,
+    messages: mpsc::UnboundedReceiver<(usize, usize, Value)>,
+    worker_cpu_percent: f64,
+}
+
+impl Controller {
+    async fn start() -> Result<Controller> {
+        let (controller_io, worker_io) = tokio::io::duplex(1 << 20);
+        let config = WorkerConfig {
+            log_level: WorkerLogLevel::Warn,
+            log_tags: vec![],
+            rtc_port_range: 20000..=59999,
+            listen_ip: "127.0.0.1".parse()?,
+            // Never announced to anyone, the fake peers find the SFU by port.
+            announce_ip: "192.0.2.1".parse()?,
+            ice_servers: vec![],
+            worker_death: WorkerDeathPolicy::Exit,
+            load_report_interval: Some(Duration::from_secs(1)),
+            snapshot_file: None,
+        };
+        let (ws, worker_ws) = tokio::join!(
+            tokio_tungstenite::accept_async(controller_io),
+            tokio_tungstenite::client_async("ws://controller/media-worker/0/loadgen", worker_io),
+        );
+        let (worker_ws, _) = worker_ws?;
+        tokio::spawn(async move {
+            if let Err(e) = run_worker(config, worker_ws).await {
+                eprintln!("media worker stopped: {e}");
+            }
+        });
+
+        let (ws, mut read) = ws?.split();
+        let (tx, messages) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = read.next().await {
+                let Message::Text(text) = message else {
+                    continue
+                };
+                if let Ok(message) = serde_json::from_str(&text) {
+                    if tx.send(message).is_err() {
+                        break
+                    }
+                }
+            }
+        });
+        Ok(Controller {
+            ws,
+            messages,
+            worker_cpu_percent: 0.0,
+        })
+    }
+
+    async fn send(&mut self, message: Value) -> Result<()> {
+        self.ws.send(Message::text(message.to_string())).await?;
+        Ok(())
+    }
+
+    /// Waits for a message about the given channel and peer that has the key, keeping
+    /// track of load reports on the way.
+    async fn wait_for(&mut self, channel: usize, peer: usize, key: &str) -> Result<Value> {
+        loop {
+            let (to_channel, to_peer, message) = tokio::time::timeout(Duration::from_secs(10), self.messages.recv()).await
+                .context("timed out waiting for the media worker")?
+                .ok_or_else(|| anyhow!("media worker closed the connection"))?;
+            self.note(&message);
+            if to_channel == channel && to_peer == peer {
+                if let Some(value) = message.get(key) {
+                    return Ok(value.clone())
+                }
+            }
+        }
+    }
+
+    /// Handles whatever arrives for a while.
+    async fn idle(&mut self, duration: Duration) {
+        let until = tokio::time::Instant::now() + duration;
+        while let Ok(Some((_, _, message))) = tokio::time::timeout_at(until, self.messages.recv()).await {
+            self.note(&message);
+        }
+    }
+
+    fn note(&mut self, message: &Value) {
+        if let Some(cpu) = message.get("loadReport").and_then(|report| report["cpuPercent"].as_f64()) {
+            self.worker_cpu_percent = cpu;
+        }
+    }
+}
+
+async fn run(options: &Options) -> Result<Vec<Row>> {
+    let tone = tone()?;
+    let mut controller = Controller::start().await?;
+    let opus = json!({"kind": "audio", "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []});
+    for channel in 0..options.channels {
+        controller.send(json!({"type": "NewChannel", "channel": channel, "codecs": [opus]})).await?;
+    }
+
+    eprintln!("{CSV_HEADER}");
+    let received = Arc::new(AtomicU64::new(0));
+    let mut peers = vec![];
+    let mut rows = vec![];
+    while peers.len() < options.peers {
+        let mut joins = vec![];
+        for _ in 0..options.step.min(options.peers - peers.len()) {
+            let peer = peers.len();
+            let channel = peer % options.channels;
+            let ssrc = 10000 + peer as u32;
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+            let started = Instant::now();
+            controller.send(json!({"type": "AddRtpPeer", "channel": channel, "peer": peer, "comedia": true, "ssrc": ssrc})).await?;
+            let created = controller.wait_for(channel, CONTROLLER, "rtpPeerCreated").await?;
+            joins.push(started.elapsed().as_secs_f64() * 1000.0);
+
+            let port = created["tuple"]["localPort"].as_u64().ok_or_else(|| anyhow!("RTP peer without a port: {created}"))?;
+            let sfu = SocketAddr::from(([127, 0, 0, 1], port as u16));
+            peers.push(tokio::spawn(fake_peer(socket, sfu, ssrc, tone.clone(), received.clone())));
+        }
+
+        // Let the new peers get going before measuring.
+        controller.idle(Duration::from_secs(1)).await;
+        let cpu_before = cpu_seconds();
+        let received_before = received.load(Ordering::Relaxed);
+        let started = Instant::now();
+        controller.idle(options.settle).await;
+        let elapsed = started.elapsed().as_secs_f64();
+        let cpu_percent = match (cpu_before, cpu_seconds()) {
+            (Some(before), Some(after)) => (after - before) / elapsed * 100.0,
+            _ => f64::NAN,
+        };
+        let packets = (received.load(Ordering::Relaxed) - received_before) as f64;
+
+        let row = Row {
+            peers: peers.len(),
+            join_ms_mean: joins.iter().sum::<f64>() / joins.len() as f64,
+            join_ms_max: joins.iter().cloned().fold(0.0, f64::max),
+            cpu_percent,
+            worker_cpu_percent: controller.worker_cpu_percent,
+            rss_kib: rss_kib().unwrap_or(0),
+            packets_per_peer_per_second: packets / elapsed / peers.len() as f64,
+        };
+        eprintln!("{}", row.to_csv());
+        rows.push(row);
+    }
+
+    for task in &peers {
+        task.abort();
+    }
+    Ok(rows)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse()?;
+    let rows = run(&options).await?;
+    let report = if options.json {
+        serde_json::to_string_pretty(&rows.iter().map(Row::to_json).collect::<Vec<_>>())?
+    } else {
+        std::iter::once(CSV_HEADER.to_string()).chain(rows.iter().map(Row::to_csv)).collect::<Vec<_>>().join("\n")
+    };
+    match &options.output {
+        Some(path) => std::fs::write(path, report + "\n").with_context(|| format!("could not write {path}"))?,
+        None => println!("{report}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Breakout rooms: a channel split into smaller rooms for a while, then merged back.
+//! Every room is a channel of its own with its own router, but one the controller never
+//! hears about. Peers are moved between the rooms the way `MovePeer` moves them, so they
+//! keep their transports, and whatever is sent about them is addressed to the channel
+//! they broke out of.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::error;
+use mediasoup::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+
+use crate::{IncomingMessage, PeerID, State, ToClient, CONTROLLER};
+
+// How long before the end everyone is told to get ready to return.
+const RETURN_WARNING: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BreakoutRoom {
+    room: usize,
+    #[serde(default)]
+    peers: Vec<PeerID>,
+}
+
+pub(crate) struct Breakout {
+    rooms: Vec<usize>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Drop for Breakout {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.timer {
+            timer.abort();
+        }
+    }
+}
+
+fn start_timer(channel: usize, seconds: u64, tx: &UnboundedSender<IncomingMessage>) -> JoinHandle<()> {
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let total = Duration::from_secs(seconds);
+        let warning = RETURN_WARNING.min(total / 2);
+        tokio::time::sleep(total - warning).await;
+        _ = tx.send(IncomingMessage::BreakoutEnding {
+            channel,
+            seconds: warning.as_secs(),
+        });
+        tokio::time::sleep(warning).await;
+        _ = tx.send(IncomingMessage::EndBreakout { channel });
+    })
+}
+
+impl State {
+    /// The rooms of the breakout a channel is in, along with the channel itself.
+    pub(crate) fn breakout_family(&self, channel: usize) -> Vec<usize> {
+        let mut family = vec![channel];
+        if let Some(breakout) = self.channels.get(&channel).and_then(|channel| channel.breakout.as_ref()) {
+            family.extend_from_slice(&breakout.rooms);
+        }
+        family
+    }
+
+    /// Tells everyone in a channel and its rooms.
+    fn broadcast_breakout(&self, channel: usize, message: impl Fn() -> ToClient, tx: &UnboundedSender<IncomingMessage>) {
+        for room in self.breakout_family(channel) {
+            _ = tx.send(IncomingMessage::BroadCast {
+                channel: room,
+                from_peer: CONTROLLER,
+                message: message(),
+            });
+        }
+    }
+
+    /// Moves a peer of a channel with a breakout to a room of it, or back to the channel.
+    async fn assign(&mut self, channel: usize, peer: PeerID, room: Option<usize>, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let from = self.current_channel(channel, peer);
+        let to = room.unwrap_or(channel);
+        if !self.breakout_family(channel).contains(&from) {
+            bail!("peer is not in the channel or one of its rooms");
+        }
+        if from != to {
+            self.move_peer(from, to, peer, tx).await?;
+        }
+        _ = tx.send(IncomingMessage::MessageTo {
+            channel: to,
+            peer,
+            message: ToClient::BreakoutAssigned { room },
+        });
+        Ok(())
+    }
+
+    pub(crate) async fn start_breakout(&mut self,
+                                       channel: usize,
+                                       rooms: Vec<BreakoutRoom>,
+                                       seconds: Option<u64>,
+                                       tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(parent) = self.channels.get(&channel) else {
+            bail!("bad channel ID");
+        };
+        if parent.parent.is_some() {
+            bail!("breakout rooms can not have breakout rooms");
+        }
+        if parent.breakout.is_some() {
+            bail!("channel already has breakout rooms");
+        }
+        for (i, room) in rooms.iter().enumerate() {
+            if self.channels.contains_key(&room.room) || rooms[..i].iter().any(|other| other.room == room.room) {
+                bail!("breakout room ID {} is taken", room.room);
+            }
+        }
+        let codecs = parent.codecs.clone();
+        let bitrate = parent.bitrate;
+
+        for room in &rooms {
+            let router = self.worker.create_router(RouterOptions::new(codecs.clone())).await?;
+            let mut child = self.new_channel(room.room, router, codecs.clone(), bitrate);
+            child.parent = Some(channel);
+            self.channels.insert(room.room, child);
+        }
+        if let Some(parent) = self.channels.get_mut(&channel) {
+            parent.breakout = Some(Breakout {
+                rooms: rooms.iter().map(|room| room.room).collect(),
+                timer: seconds.map(|seconds| start_timer(channel, seconds, tx)),
+            });
+        }
+
+        for room in &rooms {
+            for peer in &room.peers {
+                self.assign(channel, *peer, Some(room.room), tx).await?;
+            }
+        }
+        self.broadcast_breakout(channel, || ToClient::BreakoutStarted {
+            rooms: rooms.clone(),
+            seconds,
+        }, tx);
+        Ok(())
+    }
+
+    pub(crate) async fn assign_breakout(&mut self, channel: usize, peer: PeerID, room: Option<usize>, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(breakout) = self.channels.get(&channel).and_then(|channel| channel.breakout.as_ref()) else {
+            bail!("channel has no breakout rooms");
+        };
+        if room.is_some_and(|room| !breakout.rooms.contains(&room)) {
+            bail!("bad breakout room ID");
+        }
+        self.assign(channel, peer, room, tx).await
+    }
+
+    pub(crate) fn breakout_ending(&self, channel: usize, seconds: u64, tx: &UnboundedSender<IncomingMessage>) {
+        self.broadcast_breakout(channel, || ToClient::ReturnToMain { seconds }, tx);
+    }
+
+    /// Moves everyone back to the channel and closes the rooms. Peers that can not be moved
+    /// back are dropped along with their room rather than keeping the room around.
+    pub(crate) async fn end_breakout(&mut self, channel: usize, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(breakout) = self.channels.get_mut(&channel).and_then(|channel| channel.breakout.take()) else {
+            bail!("channel has no breakout rooms");
+        };
+        for room in &breakout.rooms {
+            let peers: Vec<PeerID> = self.channels.get(room).map(|room| room.peers.keys().copied().collect()).unwrap_or_default();
+            for peer in peers {
+                if let Err(e) = self.move_peer(*room, channel, peer, tx).await {
+                    error!("could not move peer {} back from breakout room {}: {}", peer, room, e);
+                    continue
+                }
+                _ = tx.send(IncomingMessage::MessageTo {
+                    channel,
+                    peer,
+                    message: ToClient::BreakoutAssigned { room: None },
+                });
+            }
+        }
+        for room in &breakout.rooms {
+            self.channels.remove(room);
+            self.moved.retain(|_, current| current != room);
+        }
+        _ = tx.send(IncomingMessage::BroadCast {
+            channel,
+            from_peer: CONTROLLER,
+            message: ToClient::BreakoutEnded {},
+        });
+        Ok(())
+    }
+}
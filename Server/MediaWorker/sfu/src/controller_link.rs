@@ -0,0 +1,202 @@
+//! How a media worker reaches its controller: a WebSocket over a Unix domain socket,
+//! plain TCP, or TLS with either a custom CA bundle or a pinned certificate.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::SinkExt;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, WebSocketStream};
+
+use crate::remote::Registration;
+use crate::{run_worker, WorkerConfig};
+
+/// Where the controller can be found, as given by `SFU_CONTROLLER_URL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControllerUrl {
+    /// `ws+unix:///path/to/socket:/http/path`
+    Unix {
+        socket: PathBuf,
+        request: String,
+    },
+
+    /// `ws://host[:port]/path` or `wss://host[:port]/path`
+    Tcp(url::Url),
+}
+
+impl FromStr for ControllerUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ControllerUrl> {
+        if let Some(rest) = s.strip_prefix("ws+unix://") {
+            let Some((socket, path)) = rest.split_once(':') else {
+                bail!("controller URL {s} is missing the HTTP path after the socket path");
+            };
+            if socket.is_empty() {
+                bail!("controller URL {s} is missing the socket path");
+            }
+            if !path.starts_with('/') {
+                bail!("controller URL {s} has an HTTP path that does not start with /");
+            }
+            return Ok(ControllerUrl::Unix {
+                socket: PathBuf::from(socket),
+                request: format!("ws://localhost{path}"),
+            });
+        }
+
+        // The form older controllers hand out: ws://unix/http/path:/path/to/socket
+        if let Some(rest) = s.strip_prefix("ws://unix/") {
+            let Some((path, socket)) = rest.split_once(':') else {
+                bail!("controller URL {s} is missing the socket path after the HTTP path");
+            };
+            return Ok(ControllerUrl::Unix {
+                socket: PathBuf::from(socket),
+                request: format!("ws://unix/{path}"),
+            });
+        }
+
+        let url = url::Url::parse(s).with_context(|| format!("invalid controller URL {s}"))?;
+        match url.scheme() {
+            "ws" | "wss" => {}
+            scheme => bail!("unsupported controller URL scheme: {scheme}"),
+        }
+        if url.host().is_none() {
+            bail!("controller URL {s} has no host");
+        }
+        Ok(ControllerUrl::Tcp(url))
+    }
+}
+
+/// Which certificates a `wss://` controller may present.
+#[derive(Debug, Clone, Default)]
+pub struct ControllerTls {
+    /// PEM file with the CAs to trust instead of the usual web roots.
+    pub ca_bundle: Option<PathBuf>,
+
+    /// SHA-256 of the DER encoded certificate of the controller. Takes precedence over
+    /// any CA, for controllers with self-signed certificates.
+    pub pinned_sha256: Option<[u8; 32]>,
+}
+
+fn parse_fingerprint(s: &str) -> Result<[u8; 32]> {
+    let hex: String = s.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.is_ascii() {
+        bail!("a SHA-256 fingerprint has 64 hex digits");
+    }
+    let mut fingerprint = [0; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(fingerprint)
+}
+
+impl ControllerTls {
+    pub fn from_env() -> Result<ControllerTls> {
+        let ca_bundle = std::env::var("SFU_CONTROLLER_CA").ok()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from);
+        let pinned_sha256 = match std::env::var("SFU_CONTROLLER_CERT_SHA256") {
+            Ok(pin) if !pin.is_empty() => Some(parse_fingerprint(&pin).context("invalid SFU_CONTROLLER_CERT_SHA256")?),
+            _ => None,
+        };
+        Ok(ControllerTls {
+            ca_bundle,
+            pinned_sha256,
+        })
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        if let Some(pin) = self.pinned_sha256 {
+            return Ok(builder
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate { sha256: pin }))
+                .with_no_client_auth());
+        }
+
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle {
+            Some(path) => {
+                let pem = std::fs::read(path).with_context(|| format!("could not read CA bundle {}", path.display()))?;
+                let certs = rustls_pemfile::certs(&mut pem.as_slice())?;
+                let (added, _) = roots.add_parsable_certificates(&certs);
+                if added == 0 {
+                    bail!("no usable certificates in CA bundle {}", path.display());
+                }
+            }
+            None => {
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+                }));
+            }
+        }
+        Ok(builder.with_root_certificates(roots).with_no_client_auth())
+    }
+}
+
+struct PinnedCertificate {
+    sha256: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self,
+                          end_entity: &Certificate,
+                          _intermediates: &[Certificate],
+                          _server_name: &ServerName,
+                          _scts: &mut dyn Iterator<Item = &[u8]>,
+                          _ocsp_response: &[u8],
+                          _now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        // The pin says exactly which certificate to expect, so names and dates do not matter.
+        if Sha256::digest(&end_entity.0).as_slice() == self.sha256 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("controller certificate does not match the pinned fingerprint".to_string()))
+        }
+    }
+}
+
+async fn register<S>(ws_stream: &mut WebSocketStream<S>, registration: Option<&Registration>) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    if let Some(registration) = registration {
+        ws_stream.send(Message::text(registration.to_json())).await
+            .map_err(|e| anyhow!("could not register with the SFU controller: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Connects to the controller and serves it until it closes the connection. Remote
+/// workers introduce themselves first.
+pub async fn serve(config: WorkerConfig, url: &ControllerUrl, tls: &ControllerTls, registration: Option<&Registration>) -> Result<()> {
+    match url {
+        #[cfg(not(windows))]
+        ControllerUrl::Unix { socket, request } => {
+            let stream = tokio::net::UnixStream::connect(socket).await
+                .with_context(|| format!("could not connect to {}", socket.display()))?;
+            let (mut ws_stream, _) = tokio_tungstenite::client_async(request.as_str(), stream).await
+                .map_err(|e| anyhow!("SFU controller connection failed (unix domain socket): {e}"))?;
+            register(&mut ws_stream, registration).await?;
+            run_worker(config, ws_stream).await
+        }
+        #[cfg(windows)]
+        ControllerUrl::Unix { .. } => {
+            bail!("domain socket support is disabled on win32");
+        }
+        ControllerUrl::Tcp(url) => {
+            let connector = match url.scheme() {
+                "wss" => Some(Connector::Rustls(Arc::new(tls.client_config()?))),
+                _ => None,
+            };
+            let (mut ws_stream, _) = tokio_tungstenite::connect_async_tls_with_config(url.as_str(), None, connector).await
+                .map_err(|e| anyhow!("SFU controller connection failed (http over tcp): {e}"))?;
+            register(&mut ws_stream, registration).await?;
+            run_worker(config, ws_stream).await
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Dial-in participants. A SIP gateway (FreeSWITCH, Asterisk, ...) bridges each call to
+//! an RTP endpoint speaking Opus, which joins the channel as a virtual peer over a
+//! `PlainTransport`. Since the far end can only take one stream, it hears the channel
+//! mixed.
+
+use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroU8};
+
+use anyhow::{bail, Result};
+use mediasoup::prelude::*;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::mixer::{opus_capabilities, MixedAudio};
+use crate::{Channel, IncomingMessage, Peer, PeerID, PeerRole, ToClient, CONTROLLER};
+
+fn default_payload_type() -> u8 {
+    111
+}
+
+/// How the SFU reaches the RTP endpoint of a call.
+#[derive(Deserialize, Debug)]
+pub(crate) struct RtpEndpoint {
+    /// Where to send the mix. Left out when `comedia` is set.
+    #[serde(default)]
+    remote: Option<SocketAddr>,
+
+    /// Send to wherever the first packet came from, for endpoints behind NAT.
+    #[serde(default)]
+    comedia: bool,
+
+    /// The SSRC and payload type the endpoint sends Opus with.
+    ssrc: u32,
+    #[serde(default = "default_payload_type")]
+    payload_type: u8,
+}
+
+pub(crate) struct RtpPeer {
+    // Closed along with the peer.
+    _transport: PlainTransport,
+}
+
+fn opus_rtp_parameters(endpoint: &RtpEndpoint, peer: PeerID) -> RtpParameters {
+    RtpParameters {
+        codecs: vec![RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type: endpoint.payload_type,
+            clock_rate: NonZeroU32::new(48000).unwrap(),
+            channels: NonZeroU8::new(2).unwrap(),
+            parameters: RtpCodecParametersParameters::default(),
+            rtcp_feedback: vec![],
+        }],
+        encodings: vec![RtpEncodingParameters {
+            ssrc: Some(endpoint.ssrc),
+            ..RtpEncodingParameters::default()
+        }],
+        rtcp: RtcpParameters {
+            cname: Some(format!("rtp-peer-{peer}")),
+            ..RtcpParameters::default()
+        },
+        ..RtpParameters::default()
+    }
+}
+
+impl Channel {
+    /// Adds a peer whose media goes through a plain RTP endpoint instead of a client.
+    pub(crate) async fn add_rtp_peer(&mut self, peer_id: PeerID, endpoint: RtpEndpoint, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        if self.peers.contains_key(&peer_id) {
+            bail!("peer ID already in channel");
+        }
+        if endpoint.remote.is_none() && !endpoint.comedia {
+            bail!("RTP peers need a remote address unless comedia is set");
+        }
+
+        let mut options = PlainTransportOptions::new(ListenInfo {
+            protocol: Protocol::Udp,
+            ip: self.listen_ip,
+            announced_address: Some(self.announce_ip.to_string()),
+            port: None,
+            port_range: None,
+            flags: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        });
+        options.rtcp_mux = true;
+        options.comedia = endpoint.comedia;
+        let transport = self.router.create_plain_transport(options).await?;
+        if let (Some(remote), false) = (endpoint.remote, endpoint.comedia) {
+            transport.connect(PlainTransportRemoteParameters {
+                ip: Some(remote.ip()),
+                port: Some(remote.port()),
+                rtcp_port: None,
+                srtp_parameters: None,
+            }).await?;
+        }
+
+        let producer = transport.produce(ProducerOptions::new(MediaKind::Audio, opus_rtp_parameters(&endpoint, peer_id))).await?;
+        let mix = MixedAudio::start(&self.router, self.audio_producers_except(peer_id), &transport, opus_capabilities(), false).await?;
+        let rtp_parameters = mix.rtp_parameters();
+
+        let mut peer = Peer::new(PeerRole::Speaker);
+        let producer_id = producer.id().to_string();
+        peer.producers.insert(producer_id.clone(), producer.clone());
+        peer.mix = Some(mix);
+        peer.rtp = Some(RtpPeer { _transport: transport.clone() });
+        self.peers.insert(peer_id, peer);
+        self.observe_producer(&producer).await?;
+        self.mix_producer(&producer, peer_id).await?;
+
+        _ = tx.send(IncomingMessage::BroadCast {
+            channel: self.channel_id,
+            from_peer: peer_id,
+            message: ToClient::NewProducers(vec![self.new_producer(peer_id, &producer_id)]),
+        });
+        _ = tx.send(IncomingMessage::MessageTo {
+            channel: self.channel_id,
+            peer: CONTROLLER,
+            message: ToClient::RtpPeerCreated {
+                peer_id,
+                tuple: transport.tuple(),
+                rtp_parameters,
+            },
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! Streams the mixed audio of a channel as Ogg/Opus over plain HTTP, the way an Icecast
+//! mount point does, so that people without an account can listen in.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use mediasoup::prelude::*;
+use opus::{Application, Channels};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::mixer::{random_ssrc, Inputs, Mixer, SourcePacket, FRAME, SAMPLE_RATE};
+use crate::{IncomingMessage, ToClient, CONTROLLER};
+
+// Samples the Opus encoder is ahead by, at 48kHz.
+const PRE_SKIP: u16 = 312;
+
+// How many pages a slow listener may fall behind before it starts losing audio.
+const BACKLOG: usize = 256;
+
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// The CRC used by Ogg: polynomial 0x04c11db7, no reflection, no final XOR.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0_u32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+const BEGINNING_OF_STREAM: u8 = 0x02;
+
+/// Wraps a single packet into an Ogg page.
+fn ogg_page(serial: u32, sequence: u32, header_type: u8, granule: u64, packet: &[u8]) -> Vec<u8> {
+    let mut lacing = vec![255_u8; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+
+    let mut page = Vec::with_capacity(27 + lacing.len() + packet.len());
+    page.extend_from_slice(b"OggS");
+    page.push(0);
+    page.push(header_type);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&serial.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    page.extend_from_slice(&[0; 4]);
+    page.push(lacing.len() as u8);
+    page.extend_from_slice(&lacing);
+    page.extend_from_slice(packet);
+    let crc = crc32(&page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+    page
+}
+
+/// The identification and comment headers every listener needs before any audio.
+fn ogg_headers(serial: u32, channel: usize) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(1);
+    head.extend_from_slice(&PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    head.extend_from_slice(&0_i16.to_le_bytes());
+    head.push(0);
+
+    let vendor = b"media-worker-sfu";
+    let title = format!("TITLE=Channel {channel}");
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&1_u32.to_le_bytes());
+    tags.extend_from_slice(&(title.len() as u32).to_le_bytes());
+    tags.extend_from_slice(title.as_bytes());
+
+    let mut headers = ogg_page(serial, 0, BEGINNING_OF_STREAM, 0, &head);
+    headers.extend(ogg_page(serial, 1, 0, 0, &tags));
+    headers
+}
+
+async fn encode_into(mut packets: UnboundedReceiver<SourcePacket>, pages: broadcast::Sender<Arc<[u8]>>, serial: u32) {
+    let mut mixer = Mixer::new();
+    let mut encoder = match opus::Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Audio) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            warn!("could not create Opus encoder: {e}");
+            return
+        }
+    };
+    let silence = vec![0_i16; FRAME];
+    let mut encoded = vec![0; 4000];
+    let mut sequence = 2;
+    let mut granule = 0_u64;
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    // Listeners would rather hear a gap than a burst of frames after a stall.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    return
+                };
+                _ = mixer.push(&packet.source, &packet.packet);
+            }
+            _ = interval.tick() => {
+                // Listeners expect a continuous stream, so silence is encoded as well.
+                let frame = mixer.next_frame();
+                let frame = frame.as_deref().unwrap_or(&silence);
+                let n = match encoder.encode(frame, &mut encoded) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        warn!("could not encode broadcast audio: {e}");
+                        continue
+                    }
+                };
+                granule += FRAME as u64;
+                // Nobody listening is not an error.
+                _ = pages.send(ogg_page(serial, sequence, 0, granule, &encoded[..n]).into());
+                sequence += 1;
+            }
+        }
+    }
+}
+
+struct Status {
+    channel: usize,
+    address: SocketAddr,
+    live: AtomicBool,
+    listeners: AtomicUsize,
+    tx: UnboundedSender<IncomingMessage>,
+}
+
+impl Status {
+    fn report(&self) {
+        if !self.live.load(Ordering::Relaxed) {
+            return
+        }
+        _ = self.tx.send(IncomingMessage::MessageTo {
+            channel: self.channel,
+            peer: CONTROLLER,
+            message: ToClient::BroadcastStatus {
+                live: true,
+                address: Some(self.address),
+                listeners: self.listeners.load(Ordering::Relaxed),
+            },
+        });
+    }
+}
+
+async fn listen(mut stream: TcpStream, headers: Arc<[u8]>, mut pages: broadcast::Receiver<Arc<[u8]>>, status: Arc<Status>) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(())
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+    if !request.starts_with(b"GET ") {
+        stream.write_all(b"HTTP/1.0 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\n\r\n").await?;
+        return Ok(())
+    }
+    let response = format!("HTTP/1.0 200 OK\r\n\
+                            Content-Type: audio/ogg\r\n\
+                            Cache-Control: no-cache, no-store\r\n\
+                            icy-name: Channel {}\r\n\
+                            Connection: close\r\n\r\n", status.channel);
+    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(&headers).await?;
+
+    status.listeners.fetch_add(1, Ordering::Relaxed);
+    status.report();
+    let result = loop {
+        match pages.recv().await {
+            Ok(page) => {
+                if let Err(e) = stream.write_all(&page).await {
+                    break Err(e.into())
+                }
+            }
+            // A gap in the audio is better than dropping the listener.
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break Ok(()),
+        }
+    };
+    status.listeners.fetch_sub(1, Ordering::Relaxed);
+    status.report();
+    result
+}
+
+async fn serve(listener: TcpListener, headers: Arc<[u8]>, pages: broadcast::Sender<Arc<[u8]>>, status: Arc<Status>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("could not accept broadcast listener: {e}");
+                continue
+            }
+        };
+        tokio::spawn(listen(stream, headers.clone(), pages.subscribe(), status.clone()));
+    }
+}
+
+pub(crate) struct Broadcast {
+    inputs: Inputs,
+    status: Arc<Status>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Broadcast {
+    fn drop(&mut self) {
+        // Listeners notice once the encoder is gone and its pages stop.
+        self.status.live.store(false, Ordering::Relaxed);
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Broadcast {
+    /// Starts serving the mix of the given producers at the address.
+    pub(crate) async fn start(router: &Router,
+                              address: SocketAddr,
+                              sources: Vec<ProducerId>,
+                              channel: usize,
+                              tx: &UnboundedSender<IncomingMessage>) -> Result<Broadcast> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let (mut inputs, packets) = Inputs::new(router).await?;
+        for producer_id in sources {
+            inputs.add(producer_id).await?;
+        }
+
+        let serial = random_ssrc();
+        let (pages, _) = broadcast::channel(BACKLOG);
+        let status = Arc::new(Status {
+            channel,
+            address,
+            live: AtomicBool::new(true),
+            listeners: AtomicUsize::new(0),
+            tx: tx.clone(),
+        });
+        let tasks = vec![
+            tokio::spawn(encode_into(packets, pages.clone(), serial)),
+            tokio::spawn(serve(listener, ogg_headers(serial, channel).into(), pages, status.clone())),
+        ];
+        status.report();
+        Ok(Broadcast {
+            inputs,
+            status,
+            tasks,
+        })
+    }
+
+    pub(crate) async fn add(&mut self, producer_id: ProducerId) -> Result<()> {
+        self.inputs.add(producer_id).await
+    }
+
+    pub(crate) fn remove(&mut self, producer_id: &str) {
+        self.inputs.remove(producer_id);
+    }
+}
@@ -0,0 +1,243 @@
+//! Simulated bad networks, for reproducing call quality bugs on one machine. Only built
+//! with the `impairment` feature.
+//!
+//! Impairing a producer sends its media out of a `PlainTransport`, through a UDP relay
+//! that drops, delays and throttles packets, and back into a second `PlainTransport`
+//! where it is produced again. Consumers created afterwards read the relayed producer.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use log::warn;
+use mediasoup::prelude::*;
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::mixer::random_ssrc;
+use crate::{Channel, IncomingMessage, PeerID};
+
+// Packets that would wait longer than this for a capped link are dropped instead.
+const MAX_QUEUE: Duration = Duration::from_millis(500);
+
+/// What to do to the packets. All zero turns the impairment off.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub(crate) struct Impairment {
+    loss_percent: f64,
+    latency_ms: u64,
+
+    // Added to or taken from the latency, uniformly. Reorders packets like real jitter does.
+    jitter_ms: u64,
+
+    // Zero for no cap.
+    bandwidth_kbps: u64,
+}
+
+impl Impairment {
+    fn is_none(&self) -> bool {
+        self.loss_percent <= 0.0 && self.latency_ms == 0 && self.jitter_ms == 0 && self.bandwidth_kbps == 0
+    }
+
+    /// How long to hold a packet before forwarding it, before any bandwidth cap.
+    fn delay(&self, random: &mut Random) -> Duration {
+        let jitter = (random.next() * 2.0 - 1.0) * self.jitter_ms as f64;
+        Duration::from_secs_f64((self.latency_ms as f64 + jitter).max(0.0) / 1000.0)
+    }
+}
+
+/// xorshift64, plenty for deciding which packets to drop.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Random {
+        Random((random_ssrc() as u64) << 32 | random_ssrc() as u64 | 1)
+    }
+
+    /// Uniform in [0, 1).
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+async fn relay(socket: Arc<UdpSocket>, sender: SocketAddr, receiver: SocketAddr, impairment: Impairment) {
+    let mut random = Random::new();
+    let mut link_free_at = Instant::now();
+    let mut buffer = vec![0; 65536];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("impairment relay stopped: {e}");
+                return
+            }
+        };
+        // RTCP from the receiving end goes back untouched.
+        if from != sender {
+            _ = socket.send_to(&buffer[..n], sender).await;
+            continue
+        }
+        if random.next() * 100.0 < impairment.loss_percent {
+            continue
+        }
+
+        let now = Instant::now();
+        let mut at = now + impairment.delay(&mut random);
+        if impairment.bandwidth_kbps > 0 {
+            let serialization = Duration::from_micros(n as u64 * 8 * 1000 / impairment.bandwidth_kbps);
+            let free_at = link_free_at.max(now) + serialization;
+            if free_at - now > MAX_QUEUE {
+                continue
+            }
+            link_free_at = free_at;
+            at += free_at - now;
+        }
+
+        let packet = buffer[..n].to_vec();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(at).await;
+            _ = socket.send_to(&packet, receiver).await;
+        });
+    }
+}
+
+fn loopback_transport(comedia: bool) -> PlainTransportOptions {
+    let mut options = PlainTransportOptions::new(ListenInfo {
+        protocol: Protocol::Udp,
+        ip: Ipv4Addr::LOCALHOST.into(),
+        announced_address: None,
+        port: None,
+        port_range: None,
+        flags: None,
+        send_buffer_size: None,
+        recv_buffer_size: None,
+    });
+    options.rtcp_mux = true;
+    options.comedia = comedia;
+    options
+}
+
+pub(crate) struct Relay {
+    router_id: RouterId,
+    producer: Producer,
+    _consumer: Consumer,
+    _transports: [PlainTransport; 2],
+    task: JoinHandle<()>,
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Relay {
+    async fn start(router: &Router, producer: &Producer, impairment: Impairment) -> Result<Relay> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let relay_address = socket.local_addr()?;
+
+        let sending = router.create_plain_transport(loopback_transport(false)).await?;
+        sending.connect(PlainTransportRemoteParameters {
+            ip: Some(relay_address.ip()),
+            port: Some(relay_address.port()),
+            rtcp_port: None,
+            srtp_parameters: None,
+        }).await?;
+        let receiving = router.create_plain_transport(loopback_transport(true)).await?;
+
+        // Whatever the router can send, it can also take back.
+        let capabilities: RtpCapabilities = serde_json::from_value(serde_json::to_value(router.rtp_capabilities())?)?;
+        let consumer = sending.consume(ConsumerOptions::new(producer.id(), capabilities)).await?;
+        let relayed = receiving.produce(ProducerOptions::new(producer.kind(), consumer.rtp_parameters().clone())).await?;
+
+        let sender = SocketAddr::from((Ipv4Addr::LOCALHOST, sending.tuple().local_port()));
+        let receiver = SocketAddr::from((Ipv4Addr::LOCALHOST, receiving.tuple().local_port()));
+        let task = tokio::spawn(relay(Arc::new(socket), sender, receiver, impairment));
+        Ok(Relay {
+            router_id: router.id(),
+            producer: relayed,
+            _consumer: consumer,
+            _transports: [sending, receiving],
+            task,
+        })
+    }
+}
+
+impl Channel {
+    /// The producer a peer should actually consume in place of the given one.
+    pub(crate) fn source_for(&self, producer_id: ProducerId, peer_id: PeerID) -> ProducerId {
+        let Some(relay) = self.impaired.get(&producer_id.to_string()) else {
+            return producer_id
+        };
+        // The relay lives on the router of the producer, peers that moved here read it unimpaired.
+        match self.peers.get(&peer_id) {
+            Some(peer) if self.router_of(peer).id() == relay.router_id => relay.producer.id(),
+            _ => producer_id,
+        }
+    }
+
+    /// Lets a producer through untouched again, closing its relay and the consumers of it.
+    pub(crate) fn stop_impairing(&mut self, producer_id: &str) {
+        if let Some(relay) = self.impaired.remove(producer_id) {
+            self.relayed.remove(&relay.producer.id().to_string());
+        }
+    }
+
+    /// Impairs the producers of a peer, or only those on one of its transports. Everyone
+    /// consuming them is told to consume them again, which picks up the change.
+    pub(crate) async fn impair(&mut self,
+                               peer_id: PeerID,
+                               transport_id: Option<String>,
+                               impairment: Impairment,
+                               tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(peer) = self.peers.get(&peer_id) else {
+            bail!("peer ID not found in channel");
+        };
+        if let Some(transport_id) = &transport_id {
+            if !peer.transports.contains_key(transport_id) {
+                bail!("transport ID not found in peer");
+            }
+        }
+        let producers: Vec<Producer> = peer.producers.iter()
+            .filter(|(id, _)| transport_id.is_none() || peer.transport_of.get(*id) == transport_id.as_ref())
+            .map(|(_, producer)| producer.clone())
+            .collect();
+        let router = self.router_of(peer).clone();
+
+        for producer in &producers {
+            let producer_id = producer.id().to_string();
+            // Consumers of an earlier relay close along with it.
+            self.stop_impairing(&producer_id);
+            if !impairment.is_none() {
+                let relay = Relay::start(&router, producer, impairment).await?;
+                self.relayed.insert(relay.producer.id().to_string(), producer_id.clone());
+                self.impaired.insert(producer_id.clone(), relay);
+            }
+
+            let mut direct = vec![];
+            for (consumer_peer, peer) in &self.peers {
+                for (consumer_id, consumer) in &peer.consumers {
+                    if consumer.producer_id() == producer.id() {
+                        direct.push((*consumer_peer, consumer_id.clone()));
+                    }
+                }
+            }
+            for (consumer_peer, consumer_id) in direct {
+                self.remove_consumer(consumer_peer, &consumer_id, tx);
+            }
+        }
+
+        // Whispers stay whispers, only their audience gets to consume them again.
+        let producer_ids: Vec<String> = producers.iter().map(|producer| producer.id().to_string()).collect();
+        self.announce_producers(peer_id, &producer_ids, tx);
+        Ok(())
+    }
+}
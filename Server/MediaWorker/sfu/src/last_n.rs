@@ -0,0 +1,164 @@
+//! Last-N forwarding: in large channels only the N most recent speakers are forwarded
+//! to each peer, the consumers of everyone else are paused until they speak up.
+
+use anyhow::Result;
+use mediasoup::active_speaker_observer::{ActiveSpeakerObserver, ActiveSpeakerObserverDominantSpeaker, ActiveSpeakerObserverOptions};
+use mediasoup::prelude::*;
+use mediasoup::rtp_observer::{RtpObserver, RtpObserverAddProducerOptions};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{Channel, IncomingMessage, Peer, PeerID, ToClient, CONTROLLER};
+
+pub(crate) struct LastN {
+    n: usize,
+    observer: ActiveSpeakerObserver,
+
+    // Most recent speaker first.
+    recent: Vec<PeerID>,
+
+    // What the peers were last told.
+    announced: Option<Vec<PeerID>>,
+}
+
+impl Channel {
+    /// Enables last-N forwarding with the given N, or disables it.
+    pub(crate) async fn set_last_n(&mut self, n: Option<usize>, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        match (n, self.last_n.is_some()) {
+            (None, false) => {}
+            (None, true) => {
+                self.last_n = None;
+                _ = tx.send(IncomingMessage::BroadCast {
+                    channel: self.channel_id,
+                    from_peer: CONTROLLER,
+                    message: ToClient::ForwardedSpeakers(None),
+                });
+            }
+            (Some(n), true) => {
+                if let Some(last_n) = &mut self.last_n {
+                    last_n.n = n;
+                }
+            }
+            (Some(n), false) => {
+                let observer = self.router.create_active_speaker_observer(ActiveSpeakerObserverOptions::default()).await?;
+                let channel_id = self.channel_id;
+                let tx_2 = tx.clone();
+                observer.on_dominant_speaker(move |speaker: &ActiveSpeakerObserverDominantSpeaker| {
+                    _ = tx_2.send(IncomingMessage::DominantSpeaker {
+                        channel: channel_id,
+                        producer_id: speaker.producer.id().to_string(),
+                    });
+                }).detach();
+                for peer in self.peers.values() {
+                    for producer in peer.producers.values() {
+                        if producer.kind() == MediaKind::Audio {
+                            observer.add_producer(RtpObserverAddProducerOptions::new(producer.id())).await?;
+                        }
+                    }
+                }
+                self.last_n = Some(LastN {
+                    n,
+                    observer,
+                    recent: vec![],
+                    announced: None,
+                });
+            }
+        }
+        self.sync_paused(tx).await
+    }
+
+    pub(crate) fn last_n(&self) -> Option<usize> {
+        self.last_n.as_ref().map(|last_n| last_n.n)
+    }
+
+    /// Lets the active speaker and priority observers know about a new producer.
+    pub(crate) async fn observe_producer(&self, producer: &Producer) -> Result<()> {
+        if let Some(last_n) = &self.last_n {
+            if producer.kind() == MediaKind::Audio {
+                last_n.observer.add_producer(RtpObserverAddProducerOptions::new(producer.id())).await?;
+            }
+        }
+        self.observe_priority(producer).await
+    }
+
+    pub(crate) async fn dominant_speaker(&mut self, producer_id: &str, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(speaker) = self.owner_of(producer_id) else {
+            return Ok(())
+        };
+        let Some(last_n) = &mut self.last_n else {
+            return Ok(())
+        };
+        last_n.recent.retain(|peer| *peer != speaker);
+        last_n.recent.insert(0, speaker);
+        self.sync_paused(tx).await
+    }
+
+    /// The peer a producer belongs to, looking through impairment relays.
+    pub(crate) fn owner_of(&self, producer_id: &str) -> Option<PeerID> {
+        let producer_id = self.original_producer(producer_id);
+        self.peers.iter()
+            .find(|(_, peer)| peer.producers.contains_key(&producer_id))
+            .map(|(peer_id, _)| *peer_id)
+    }
+
+    /// The peers whose producers are forwarded, or None if everyone is. Priority
+    /// speakers that are talking go first. Until someone still in the channel has
+    /// spoken there is nobody to pick, so everyone is forwarded.
+    fn forwarded(&self) -> Option<Vec<PeerID>> {
+        let last_n = self.last_n.as_ref()?;
+        if !last_n.recent.iter().any(|peer| self.peers.contains_key(peer)) {
+            return None
+        }
+        let priority = self.priority_speaking();
+        Some(priority.iter()
+            .chain(last_n.recent.iter().filter(|peer| !priority.contains(peer)))
+            .filter(|peer| self.peers.contains_key(peer))
+            .take(last_n.n)
+            .copied()
+            .collect())
+    }
+
+    /// Whether a consumer of `peer` reading from a producer owned by `owner` should be flowing.
+    pub(crate) fn should_forward(&self, peer: &Peer, owner: Option<PeerID>) -> bool {
+        if peer.deaf {
+            return false
+        }
+        match (self.forwarded(), owner) {
+            (None, _) => true,
+            (Some(forwarded), Some(owner)) => forwarded.contains(&owner),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Pauses and resumes consumers to match the deafen flags and the last-N policy.
+    pub(crate) async fn sync_paused(&mut self, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        for peer in self.peers.values() {
+            // The mix has no single owner, so only deafening pauses it.
+            if let Some(mix) = &peer.mix {
+                mix.set_paused(peer.deaf).await?;
+            }
+            for consumer in peer.consumers.values() {
+                let owner = self.owner_of(&consumer.producer_id().to_string());
+                let forward = self.should_forward(peer, owner);
+                // TODO: Do this in parallel for each consumer and then join!
+                if forward && consumer.paused() {
+                    consumer.resume().await?;
+                } else if !forward && !consumer.paused() {
+                    consumer.pause().await?;
+                }
+            }
+        }
+
+        let forwarded = self.forwarded();
+        if let Some(last_n) = &mut self.last_n {
+            if last_n.announced != forwarded {
+                last_n.announced = forwarded.clone();
+                _ = tx.send(IncomingMessage::BroadCast {
+                    channel: self.channel_id,
+                    from_peer: CONTROLLER,
+                    message: ToClient::ForwardedSpeakers(forwarded),
+                });
+            }
+        }
+        Ok(())
+    }
+}
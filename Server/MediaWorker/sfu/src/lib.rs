@@ -1,7 +1,8 @@
 use mediasoup::prelude::*;
-use mediasoup::worker::{WorkerLogLevel, WorkerLogTag};
+use mediasoup::data_structures::{IceState, TransportTuple};
+pub use mediasoup::worker::{WorkerLogLevel, WorkerLogTag};
 use futures_util::SinkExt;
-use log::error;
+use log::{debug, error, info, warn};
 
 // perhaps use https://crates.io/crates/fastwebsockets instead?
 
@@ -10,7 +11,40 @@ use std::collections::HashMap;
 use anyhow::{bail, Result};
 use tokio::sync::mpsc::UnboundedSender;
 
-type PeerID = usize;
+mod sfu;
+pub use sfu::{Sfu, SfuBuilder, SfuHandle, SfuStats, SimulatedEvent, WorkerDeathPolicy, WorkerDied};
+
+pub mod standalone;
+pub mod controller_link;
+pub mod remote;
+mod trace;
+mod last_n;
+mod profile;
+mod turn;
+mod moving;
+mod mixer;
+mod icecast;
+mod gateway;
+mod snapshot;
+mod whisper;
+mod priority;
+mod breakout;
+#[cfg(feature = "impairment")]
+mod impair;
+pub use turn::IceServer;
+pub use snapshot::SnapshotFile;
+
+pub type PeerID = usize;
+
+/// Addresses a message to the controller itself rather than to one of its peers.
+const CONTROLLER: PeerID = PeerID::MAX;
+
+/// Used in place of a channel ID for messages about the worker as a whole.
+const NO_CHANNEL: usize = usize::MAX;
+
+/// What the media worker exits with once its mediasoup worker died, so that whatever
+/// supervises it can tell a crash of the worker apart from a lost controller.
+pub const WORKER_DIED_EXIT_CODE: i32 = 3;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct NewProducer {
@@ -19,37 +53,352 @@ struct NewProducer {
 
     #[serde(rename = "producerID")]
     producer_id: String,
+
+    #[serde(rename = "appData", default, skip_serializing_if = "Option::is_none")]
+    app_data: Option<AppData>,
+}
+
+/// Whatever a client attached to a producer, such as a label, where the audio comes from
+/// or whether it is muted. Passed on to the others as is.
+type AppData = serde_json::Map<String, serde_json::Value>;
+
+// Serialized, so that app data stays small next to the rest of a message.
+const MAX_APP_DATA_BYTES: usize = 1024;
+
+fn check_app_data(app_data: &AppData) -> Result<()> {
+    if serde_json::to_vec(app_data)?.len() > MAX_APP_DATA_BYTES {
+        bail!("producer app data is larger than {} bytes", MAX_APP_DATA_BYTES);
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+enum PeerRole {
+    #[default]
+    Speaker,
+
+    // May consume but never produce.
+    Listener,
 }
 
 struct Peer {
     deaf: bool,
+    role: PeerRole,
+    hand_raised: bool,
+
+    // Ducks everyone else while speaking.
+    priority: bool,
+
     transports: HashMap<String, WebRtcTransport>,
     producers: HashMap<String, Producer>,
     consumers: HashMap<String, Consumer>,
+
+    // Maps producer and consumer IDs to the ID of the transport they were created on.
+    transport_of: HashMap<String, String>,
+
+    // Keyed by producer ID.
+    traces: HashMap<String, trace::Trace>,
+
+    // The only peers that may hear a producer, keyed by producer ID. Unrestricted producers are left out.
+    audiences: HashMap<String, Vec<PeerID>>,
+
+    // What the client attached to its producers, keyed by producer ID.
+    app_data: HashMap<String, AppData>,
+
+    // Set once the peer has moved away from the channel its transports were created in.
+    transport_router: Option<Router>,
+
+    // Everyone else mixed into one consumer, for peers that asked for it.
+    mix: Option<mixer::MixedAudio>,
+
+    // Set for dial-in peers, which have no client of their own.
+    rtp: Option<gateway::RtpPeer>,
+}
+
+impl Peer {
+    fn new(role: PeerRole) -> Peer {
+        Peer {
+            deaf: false,
+            role,
+            hand_raised: false,
+            priority: false,
+            transports: HashMap::new(),
+            producers: HashMap::new(),
+            consumers: HashMap::new(),
+            transport_of: HashMap::new(),
+            traces: HashMap::new(),
+            audiences: HashMap::new(),
+            app_data: HashMap::new(),
+            transport_router: None,
+            mix: None,
+            rtp: None,
+        }
+    }
+
+    fn created_on(&self, transport_id: &str) -> Vec<String> {
+        self.transport_of.iter()
+            .filter(|(_, t)| t.as_str() == transport_id)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
 }
 
 struct Channel {
     channel_id: usize,
     router: Router,
+    codecs: Vec<RtpCodecCapability>,
     peers: HashMap<PeerID, Peer>,
+    last_n: Option<last_n::LastN>,
+    bitrate: profile::BitrateCaps,
+
+    // Only set up once the channel had a priority speaker.
+    priority: Option<priority::Priority>,
+
+    // Producers piped between this router and the routers of peers that moved here,
+    // keyed by producer ID and the router piped to.
+    pipes: HashMap<(String, RouterId), PipeProducerToRouterResult>,
+
+    broadcast: Option<icecast::Broadcast>,
+
+    // Set for breakout rooms, which the controller knows by the channel they broke out of.
+    parent: Option<usize>,
+    breakout: Option<breakout::Breakout>,
+
+    // Relays that degrade producers on purpose, keyed by the ID of the original producer.
+    #[cfg(feature = "impairment")]
+    impaired: HashMap<String, impair::Relay>,
+    // The other way around, original producer IDs keyed by the ID of their relayed producer.
+    #[cfg(feature = "impairment")]
+    relayed: HashMap<String, String>,
 
     listen_ip: std::net::IpAddr,
     announce_ip: std::net::IpAddr,
+    ice_servers: Vec<turn::IceServer>,
 }
 
 impl Channel {
-    fn get_producers(&self) -> Vec<NewProducer> {
+    /// The channel ID the controller knows this channel by.
+    fn reported_id(&self) -> usize {
+        self.parent.unwrap_or(self.channel_id)
+    }
+
+    /// Describes a producer of a peer to the others.
+    fn new_producer(&self, peer_id: PeerID, producer_id: &str) -> NewProducer {
+        NewProducer {
+            peer_id,
+            producer_id: producer_id.to_string(),
+            app_data: self.peers.get(&peer_id).and_then(|peer| peer.app_data.get(producer_id)).cloned(),
+        }
+    }
+
+    /// Replaces what a peer attached to one of its producers, such as when it mutes, and
+    /// tells everyone who may hear the producer.
+    fn set_app_data(&mut self, peer_id: PeerID, producer_id: &str, app_data: AppData, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        check_app_data(&app_data)?;
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            bail!("peer ID not found in channel");
+        };
+        if !peer.producers.contains_key(producer_id) {
+            bail!("producer ID not found in peer");
+        }
+        peer.app_data.insert(producer_id.to_string(), app_data);
+        for (other, other_data) in &self.peers {
+            if *other == peer_id || other_data.rtp.is_some() || !self.may_consume(*other, producer_id) {
+                continue
+            }
+            _ = tx.send(IncomingMessage::MessageTo {
+                channel: self.channel_id,
+                peer: *other,
+                message: ToClient::ProducerUpdated(self.new_producer(peer_id, producer_id)),
+            });
+        }
+        Ok(())
+    }
+
+    /// The producers a peer may hear, of the given kinds only if there are any.
+    fn get_producers(&self, viewer: PeerID, kinds: Option<&[MediaKind]>) -> Vec<NewProducer> {
         let mut results = vec![];
         for (peer_id, peer) in &self.peers {
-            for producer_id in peer.producers.keys() {
-                results.push(NewProducer {
-                    peer_id: *peer_id,
-                    producer_id: producer_id.clone()
-                });
+            for (producer_id, producer) in &peer.producers {
+                if !self.may_consume(viewer, producer_id) {
+                    continue
+                }
+                if kinds.is_some_and(|kinds| !kinds.contains(&producer.kind())) {
+                    continue
+                }
+                results.push(self.new_producer(*peer_id, producer_id));
             }
         }
         results
     }
+
+    fn speakers(&self) -> Vec<PeerID> {
+        let mut speakers: Vec<PeerID> = self.peers.iter()
+            .filter(|(_, peer)| peer.role == PeerRole::Speaker)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        speakers.sort_unstable();
+        speakers
+    }
+
+    fn has_listeners(&self) -> bool {
+        self.peers.values().any(|peer| peer.role == PeerRole::Listener)
+    }
+
+    /// Without the `impairment` feature peers always consume the producer itself.
+    #[cfg(not(feature = "impairment"))]
+    fn source_for(&self, producer_id: ProducerId, _peer_id: PeerID) -> ProducerId {
+        producer_id
+    }
+
+    /// Closes a consumer and tells its peer about it. Returns false if the consumer was already gone.
+    fn remove_consumer(&mut self, peer_id: PeerID, consumer_id: &str, tx: &UnboundedSender<IncomingMessage>) -> bool {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            return false
+        };
+        if peer.consumers.remove(consumer_id).is_none() {
+            return false
+        }
+        peer.transport_of.remove(consumer_id);
+        _ = tx.send(IncomingMessage::MessageTo {
+            channel: self.channel_id,
+            peer: peer_id,
+            message: ToClient::ConsumerClosed(consumer_id.to_string()),
+        });
+        true
+    }
+
+    /// The producer a consumer was asked for, which is not the one it reads when that one is impaired.
+    fn original_producer(&self, producer_id: &str) -> String {
+        #[cfg(feature = "impairment")]
+        if let Some(original) = self.relayed.get(producer_id) {
+            return original.clone()
+        }
+        producer_id.to_string()
+    }
+
+    /// Closes every consumer in the channel that reads from the given producer, or from a relay of it.
+    fn remove_consumers_of(&mut self, producer_id: &str, tx: &UnboundedSender<IncomingMessage>) {
+        let mut closed = vec![];
+        for (peer_id, peer) in &self.peers {
+            for (consumer_id, consumer) in &peer.consumers {
+                if self.original_producer(&consumer.producer_id().to_string()) == producer_id {
+                    closed.push((*peer_id, consumer_id.clone()));
+                }
+            }
+        }
+        for (peer_id, consumer_id) in closed {
+            self.remove_consumer(peer_id, &consumer_id, tx);
+        }
+        self.unmix_producer(producer_id);
+    }
+
+    /// Closes a producer along with all consumers of it. Returns false if the producer was already gone.
+    fn remove_producer(&mut self, peer_id: PeerID, producer_id: &str, tx: &UnboundedSender<IncomingMessage>) -> bool {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            return false
+        };
+        if peer.producers.remove(producer_id).is_none() {
+            return false
+        }
+        peer.transport_of.remove(producer_id);
+        peer.traces.remove(producer_id);
+        peer.audiences.remove(producer_id);
+        peer.app_data.remove(producer_id);
+        self.pipes.retain(|(piped, _), _| piped != producer_id);
+        self.remove_consumers_of(producer_id, tx);
+        #[cfg(feature = "impairment")]
+        self.stop_impairing(producer_id);
+        _ = tx.send(IncomingMessage::BroadCast {
+            channel: self.channel_id,
+            from_peer: peer_id,
+            message: ToClient::ProducerClosed {
+                peer_id,
+                producer_id: producer_id.to_string(),
+            },
+        });
+        true
+    }
+
+    /// Closes a transport along with every producer and consumer that was created on it.
+    fn remove_transport(&mut self, peer_id: PeerID, transport_id: &str, tx: &UnboundedSender<IncomingMessage>) -> bool {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            return false
+        };
+        if peer.transports.remove(transport_id).is_none() {
+            return false
+        }
+        if peer.mix.as_ref().is_some_and(|mix| mix.transport_id == transport_id) {
+            peer.mix = None;
+        }
+        for id in peer.created_on(transport_id) {
+            if !self.remove_producer(peer_id, &id, tx) {
+                self.remove_consumer(peer_id, &id, tx);
+            }
+        }
+        true
+    }
+
+    /// Takes a peer out of the channel, closing the consumers others had of its producers,
+    /// then tells the remaining peers that its producers are gone and that it left.
+    fn take_peer(&mut self, peer_id: PeerID, tx: &UnboundedSender<IncomingMessage>) -> Option<Peer> {
+        let peer = self.peers.remove(&peer_id)?;
+        for producer_id in peer.producers.keys() {
+            self.remove_consumers_of(producer_id, tx);
+            _ = tx.send(IncomingMessage::BroadCast {
+                channel: self.channel_id,
+                from_peer: peer_id,
+                message: ToClient::ProducerClosed {
+                    peer_id,
+                    producer_id: producer_id.clone(),
+                },
+            });
+        }
+
+        // Pipes towards a router are only needed while some peer still uses it.
+        let own = self.router.id();
+        let in_use: Vec<RouterId> = self.peers.values()
+            .filter_map(|peer| peer.transport_router.as_ref().map(|router| router.id()))
+            .collect();
+        self.pipes.retain(|(producer_id, router), _| {
+            !peer.producers.contains_key(producer_id) && (*router == own || in_use.contains(router))
+        });
+        #[cfg(feature = "impairment")]
+        for producer_id in peer.producers.keys() {
+            self.stop_impairing(producer_id);
+        }
+
+        _ = tx.send(IncomingMessage::BroadCast {
+            channel: self.channel_id,
+            from_peer: peer_id,
+            message: ToClient::PeerLeft(peer_id),
+        });
+        Some(peer)
+    }
+
+    /// Removes a peer and everything it owns.
+    fn remove_peer(&mut self, peer_id: PeerID, tx: &UnboundedSender<IncomingMessage>) -> bool {
+        self.take_peer(peer_id, tx).is_some()
+    }
+
+    /// Moves the channel onto a new router after the old one died along with its worker.
+    /// The peers are gone as well, their transports having lived on the dead worker.
+    async fn reset(&mut self, router: Router, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let last_n = self.last_n();
+        self.router = router;
+        self.peers.clear();
+        self.pipes.clear();
+        self.broadcast = None;
+        self.priority = None;
+        self.breakout = None;
+        #[cfg(feature = "impairment")]
+        self.impaired.clear();
+        #[cfg(feature = "impairment")]
+        self.relayed.clear();
+        self.last_n = None;
+        self.set_last_n(last_n, tx).await
+    }
 }
 
 struct State {
@@ -58,6 +407,35 @@ struct State {
 
     listen_ip: std::net::IpAddr,
     announce_ip: std::net::IpAddr,
+    ice_servers: Vec<turn::IceServer>,
+
+    // Where peers that moved between channels went, keyed by the channel they left.
+    moved: HashMap<(usize, PeerID), usize>,
+}
+
+impl State {
+    fn new_channel(&self, channel_id: usize, router: Router, codecs: Vec<RtpCodecCapability>, bitrate: profile::BitrateCaps) -> Channel {
+        Channel {
+            channel_id,
+            router,
+            codecs,
+            peers: HashMap::new(),
+            last_n: None,
+            bitrate,
+            priority: None,
+            pipes: HashMap::new(),
+            broadcast: None,
+            parent: None,
+            breakout: None,
+            #[cfg(feature = "impairment")]
+            impaired: HashMap::new(),
+            #[cfg(feature = "impairment")]
+            relayed: HashMap::new(),
+            announce_ip: self.announce_ip,
+            listen_ip: self.listen_ip,
+            ice_servers: self.ice_servers.clone(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -90,12 +468,40 @@ enum FromClient {
         #[serde(rename = "rtpParameters")]
         rtp_parameters: RtpParameters,
 
+        // Whispers to these peers only, instead of the whole channel.
+        #[serde(default)]
+        audience: Option<Vec<PeerID>>,
+
+        #[serde(rename = "appData", default)]
+        app_data: Option<AppData>,
+
         errand: usize,
     },
+    SetProducerAudience {
+        #[serde(rename = "producerID")]
+        producer_id: String,
+
+        // Everyone may hear the producer again when left out.
+        #[serde(default)]
+        audience: Option<Vec<PeerID>>,
+    },
+    SetProducerAppData {
+        #[serde(rename = "producerID")]
+        producer_id: String,
+
+        #[serde(rename = "appData")]
+        app_data: AppData,
+    },
     ProducerClosed {
         #[serde(rename = "producerID")]
         producer_id: String,
     },
+    RestartIce {
+        #[serde(rename = "transportID")]
+        transport_id: String,
+
+        errand: usize,
+    },
     ConsumeProducer {
         #[serde(rename = "rtpCapabilities")]
         rtp_capabilities: RtpCapabilities,
@@ -110,7 +516,22 @@ enum FromClient {
         #[serde(rename = "consumerID")]
         consumer_id: String,
     },
-    GetProducers {},
+    GetProducers {
+        // Only producers of these kinds, instead of all of them.
+        #[serde(default)]
+        kinds: Option<Vec<MediaKind>>,
+    },
+    RaiseHand {
+        raised: bool,
+    },
+    RequestMixedAudio {
+        #[serde(rename = "rtpCapabilities")]
+        rtp_capabilities: RtpCapabilities,
+
+        #[serde(rename = "consumerTransportID")]
+        consumer_transport_id: String,
+    },
+    StopMixedAudio {},
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -120,6 +541,9 @@ struct TransportOptions {
     ice_parameters: IceParameters,
     ice_candidates: Vec<IceCandidate>,
     dtls_parameters: DtlsParameters,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ice_servers: Vec<turn::ClientIceServer>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -127,7 +551,57 @@ struct TransportOptions {
 enum ToClient {
      Capabilities(RtpCapabilitiesFinalized),
      NewProducers(Vec<NewProducer>),
+     // A producer that was announced before, with new app data.
+     ProducerUpdated(NewProducer),
      ConsumerClosed(String),
+     ProducerClosed {
+        #[serde(rename = "peerID")]
+        peer_id: PeerID,
+
+        #[serde(rename = "producerID")]
+        producer_id: String,
+     },
+     PeerLeft(PeerID),
+     Moved {
+        channel: usize,
+        capabilities: RtpCapabilitiesFinalized,
+     },
+     Speakers(Vec<PeerID>),
+     ForwardedSpeakers(Option<Vec<PeerID>>),
+
+     BreakoutStarted {
+        rooms: Vec<breakout::BreakoutRoom>,
+        seconds: Option<u64>,
+     },
+     // None for the main room.
+     BreakoutAssigned {
+        room: Option<usize>,
+     },
+     ReturnToMain {
+        seconds: u64,
+     },
+     BreakoutEnded {},
+
+     // Everyone but the speakers should be played this much quieter, 1 being no ducking.
+     Duck {
+        factor: f32,
+        speakers: Vec<PeerID>,
+     },
+     HandRaised {
+        #[serde(rename = "peerID")]
+        peer_id: PeerID,
+
+        raised: bool,
+     },
+     ProducerTrace {
+        #[serde(rename = "peerID")]
+        peer_id: PeerID,
+
+        #[serde(rename = "producerID")]
+        producer_id: String,
+
+        event: trace::TraceEvent,
+     },
      TransportCreated {
         errand: usize,
         data: TransportOptions,
@@ -135,6 +609,24 @@ enum ToClient {
      TransportConnected {
         errand: usize,
      },
+     IceRestarted {
+        errand: usize,
+
+        #[serde(rename = "iceParameters")]
+        ice_parameters: IceParameters,
+     },
+     IceStateChanged {
+        #[serde(rename = "transportID")]
+        transport_id: String,
+
+        state: IceState,
+     },
+     IceSelectedTupleChanged {
+        #[serde(rename = "transportID")]
+        transport_id: String,
+
+        tuple: TransportTuple,
+     },
      TransportProducing {
         errand: usize,
 
@@ -151,6 +643,53 @@ enum ToClient {
         #[serde(rename = "rtpParameters")]
         rtp_parameters: RtpParameters,
      },
+     BroadcastStatus {
+        live: bool,
+        address: Option<std::net::SocketAddr>,
+        listeners: usize,
+     },
+     RtpPeerCreated {
+        #[serde(rename = "peerID")]
+        peer_id: PeerID,
+
+        tuple: TransportTuple,
+
+        #[serde(rename = "rtpParameters")]
+        rtp_parameters: RtpParameters,
+     },
+     LoadReport {
+        channels: usize,
+        peers: usize,
+        producers: usize,
+        consumers: usize,
+
+        // Of one core, since the previous report.
+        #[serde(rename = "cpuPercent")]
+        cpu_percent: f64,
+
+        #[serde(rename = "freeRtcPorts")]
+        free_rtc_ports: usize,
+     },
+     Snapshot(snapshot::Snapshot),
+
+     // Left on disk by the worker that ran before this one, sent once on startup.
+     PreviousSnapshot(snapshot::Snapshot),
+     WorkerDied {
+        reason: String,
+        channels: Vec<usize>,
+
+        // Whether the channels are back, if empty, on a new worker.
+        recreated: bool,
+     },
+     MixedAudio {
+        id: String,
+
+        #[serde(rename = "producerID")]
+        producer_id: String,
+
+        #[serde(rename = "rtpParameters")]
+        rtp_parameters: RtpParameters,
+     },
      Nothing,
 }
 
@@ -162,16 +701,65 @@ struct ToServer(usize, PeerID, ToClient);
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 enum IncomingMessage {
-    NewChannel {channel: usize, codecs: Vec<RtpCodecCapability>},
-    AddPeer {channel: usize, peer: PeerID},
+    NewChannel {
+        channel: usize,
+        // Left empty when a profile provides the codecs.
+        #[serde(default)] codecs: Vec<RtpCodecCapability>,
+        #[serde(default)] profile: Option<profile::ChannelProfile>,
+        #[serde(default)] bitrate: Option<profile::BitrateCaps>,
+    },
+    // Along with its breakout rooms, if it has any.
+    RemoveChannel {channel: usize},
+    AddPeer {channel: usize, peer: PeerID, #[serde(default)] role: PeerRole},
     RemovePeer {channel: usize, peer: PeerID},
+    AddRtpPeer {channel: usize, peer: PeerID, #[serde(flatten)] endpoint: gateway::RtpEndpoint},
+    MovePeer {from: usize, to: usize, peer: PeerID},
     RemoveTransport {channel: usize, peer: PeerID, transport_id: String},
     HandleClient {channel: usize, peer: PeerID, message: FromClient},
     SetDeafenPeer {channel: usize, peer: PeerID, deafen: bool},
+    SetPeerRole {channel: usize, peer: PeerID, role: PeerRole},
+    SetLastN {channel: usize, last_n: Option<usize>},
+    SetPrioritySpeaker {channel: usize, peer: PeerID, priority: bool},
+    StartBreakout {channel: usize, rooms: Vec<breakout::BreakoutRoom>, #[serde(default)] seconds: Option<u64>},
+    AssignBreakout {channel: usize, peer: PeerID, room: Option<usize>},
+    EndBreakout {channel: usize},
+    SetChannelBitrate {channel: usize, bitrate: profile::BitrateCaps},
+    TraceProducer {channel: usize, peer: PeerID, producer_id: String, events: Vec<String>, seconds: u64, max_per_second: u32},
+    StopTrace {channel: usize, peer: PeerID, producer_id: String},
+    StartBroadcast {channel: usize, address: std::net::SocketAddr},
+    StopBroadcast {channel: usize},
+    Snapshot,
+    #[cfg(feature = "impairment")]
+    ImpairNetwork {
+        channel: usize,
+        peer: PeerID,
+        // Every producer of the peer when left out.
+        #[serde(default)] transport_id: Option<String>,
+        #[serde(flatten)] impairment: impair::Impairment,
+    },
 
-    // Used internally by the SFU.
+    // Used internally by the SFU, never read from the controller.
+    #[serde(skip_deserializing)]
     BroadCast {channel: usize, from_peer: PeerID, message: ToClient},
+    #[serde(skip_deserializing)]
     MessageTo {channel: usize, peer: PeerID, message: ToClient},
+    #[serde(skip_deserializing)]
+    TraceExpired {channel: usize, peer: PeerID, producer_id: String},
+    #[serde(skip_deserializing)]
+    DominantSpeaker {channel: usize, producer_id: String},
+    #[serde(skip_deserializing)]
+    PriorityVolumes {channel: usize, producer_ids: Vec<String>},
+    #[serde(skip_deserializing)]
+    BreakoutEnding {channel: usize, seconds: u64},
+    #[serde(skip_deserializing)]
+    ControllerClosed,
+    #[serde(skip_deserializing)]
+    WorkerDied {reason: String},
+    #[serde(skip_deserializing)]
+    ReportLoad,
+    #[serde(skip_deserializing)]
+    WriteSnapshot,
+    // Read from the link in place of WebSocket pings, see HEARTBEAT_REQUEST.
     Heartbeat,
 }
 
@@ -201,7 +789,7 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                 bail!("announce_ip set to unallowed IP value");
             }
 
-            let transport = channel.router.create_webrtc_transport(WebRtcTransportOptions::new(WebRtcTransportListenInfos::new(
+            let mut options = WebRtcTransportOptions::new(WebRtcTransportListenInfos::new(
                 ListenInfo {
                     protocol: Protocol::Udp,
                     ip: channel.listen_ip,
@@ -223,7 +811,12 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                     send_buffer_size: None,
                     recv_buffer_size: None,                   
                 }
-            ))).await?;
+            ));
+            if let Some(bitrate) = channel.bitrate.initial_available_outgoing_bitrate {
+                options.initial_available_outgoing_bitrate = bitrate;
+            }
+            let transport = channel.router.create_webrtc_transport(options).await?;
+            profile::apply_bitrate_caps(&transport, channel.bitrate).await?;
             let transport_id = transport.id().to_string();
             let result = ToClient::TransportCreated {
                 errand,
@@ -232,15 +825,44 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                     ice_candidates: transport.ice_candidates().clone(),
                     ice_parameters: transport.ice_parameters().clone(),
                     dtls_parameters: transport.dtls_parameters(),
+                    ice_servers: channel.ice_servers.iter()
+                        .map(|server| server.for_peer(channel.channel_id, peer_id))
+                        .collect(),
                 }
             };
             let channel_id = channel.channel_id;
             let transport_id = transport.id().to_string();
             let transport_id_2 = transport_id.clone();
 
+            // Lets the client restart ICE before DTLS gives up on the transport.
+            let tx_2 = tx.clone();
+            let transport_id_3 = transport_id.clone();
+            transport.on_ice_state_change(move |state| {
+                _ = tx_2.send(IncomingMessage::MessageTo {
+                    channel: channel_id,
+                    peer: peer_id,
+                    message: ToClient::IceStateChanged {
+                        transport_id: transport_id_3.clone(),
+                        state,
+                    },
+                });
+            }).detach();
+            let tx_3 = tx.clone();
+            let transport_id_4 = transport_id.clone();
+            transport.on_ice_selected_tuple_change(move |tuple| {
+                _ = tx_3.send(IncomingMessage::MessageTo {
+                    channel: channel_id,
+                    peer: peer_id,
+                    message: ToClient::IceSelectedTupleChanged {
+                        transport_id: transport_id_4.clone(),
+                        tuple: tuple.clone(),
+                    },
+                });
+            }).detach();
+
             let tx = tx.clone();
             transport.on_dtls_state_change(move |s| {
-                println!("transport closed: {:#?}", s);
+                debug!("transport {} DTLS state: {:?}", transport_id, s);
                 match s {
                     mediasoup::data_structures::DtlsState::Closed => {
                         _ = tx.send(IncomingMessage::RemoveTransport {
@@ -270,10 +892,16 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                 errand
             }
         }
-        FromClient::ProduceTransport{producer_transport_id, kind, rtp_parameters, errand} => {
+        FromClient::ProduceTransport{producer_transport_id, kind, rtp_parameters, audience, app_data, errand} => {
+            if let Some(app_data) = &app_data {
+                check_app_data(app_data)?;
+            }
             let Some(peer) = channel.peers.get_mut(&peer_id) else {
                 bail!("peer ID not found in channel");
             };
+            if peer.role == PeerRole::Listener {
+                bail!("listeners may not produce");
+            }
             let Some(transport) = peer.transports.get_mut(&producer_transport_id) else {
                 bail!("transport ID not found in peer");
             };
@@ -292,16 +920,18 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                     }
                 });
             }).detach();
-            peer.producers.insert(producer_id.clone(), producer);
-
-            _ = tx.send(IncomingMessage::BroadCast {
-                channel: channel.channel_id,
-                from_peer: peer_id,
-                message: ToClient::NewProducers(vec![NewProducer {
-                    peer_id: peer_id,
-                    producer_id: producer_id.clone()
-                }])
-            });
+            peer.producers.insert(producer_id.clone(), producer.clone());
+            peer.transport_of.insert(producer_id.clone(), producer_transport_id);
+            if let Some(audience) = audience {
+                peer.audiences.insert(producer_id.clone(), audience);
+            }
+            if let Some(app_data) = app_data {
+                peer.app_data.insert(producer_id.clone(), app_data);
+            }
+            channel.pipe_from_peer(&producer, peer_id).await?;
+            channel.observe_producer(&producer).await?;
+            channel.mix_producer(&producer, peer_id).await?;
+            channel.announce_producers(peer_id, &[producer_id.clone()], tx);
 
 
             ToClient::TransportProducing {
@@ -309,24 +939,58 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                 producer_id
             }
         }
+        FromClient::SetProducerAudience{producer_id, audience} => {
+            channel.set_audience(peer_id, &producer_id, audience, tx).await?;
+            ToClient::Nothing
+        }
+        FromClient::SetProducerAppData{producer_id, app_data} => {
+            channel.set_app_data(peer_id, &producer_id, app_data, tx)?;
+            ToClient::Nothing
+        }
         FromClient::ProducerClosed{producer_id} => {
+            // Also sent by the SFU itself once a transport closes, in which case
+            // the producer might already have been cleaned up.
+            channel.remove_producer(peer_id, &producer_id, tx);
+            ToClient::Nothing
+        }
+        FromClient::RestartIce{transport_id, errand} => {
             let Some(peer) = channel.peers.get_mut(&peer_id) else {
                 bail!("peer ID not found in channel");
             };
-            peer.producers.remove(&producer_id);
-            ToClient::Nothing
+            let Some(transport) = peer.transports.get(&transport_id) else {
+                bail!("transport ID not found in peer");
+            };
+            ToClient::IceRestarted {
+                errand,
+                ice_parameters: transport.restart_ice().await?,
+            }
         }
         FromClient::ConsumeProducer{rtp_capabilities, consumer_transport_id, producer_id} => {
-            if !channel.router.can_consume(&producer_id, &rtp_capabilities) {
-                bail!("router can not consume provided capabilities");
+            if !channel.may_consume(peer_id, &producer_id.to_string()) {
+                bail!("peer is not in the audience of the producer");
+            }
+            if channel.is_mixed_for(peer_id, &producer_id.to_string()) {
+                bail!("peer receives audio mixed");
             }
+            channel.pipe_to_peer(producer_id, peer_id).await?;
+            let owner = channel.owner_of(&producer_id.to_string());
+            let source = channel.source_for(producer_id, peer_id);
+            let forward = match channel.peers.get(&peer_id) {
+                Some(peer) => {
+                    if !channel.router_of(peer).can_consume(&source, &rtp_capabilities) {
+                        bail!("router can not consume provided capabilities");
+                    }
+                    channel.should_forward(peer, owner)
+                }
+                None => bail!("peer ID not found in channel"),
+            };
             let Some(peer) = channel.peers.get_mut(&peer_id) else {
                 bail!("peer ID not found in channel");
             };
             let Some(transport) = peer.transports.get_mut(&consumer_transport_id) else {
                 bail!("transport ID not found in peer");
             };
-            let consumer = transport.consume(ConsumerOptions::new(producer_id, rtp_capabilities)).await?;
+            let consumer = transport.consume(ConsumerOptions::new(source, rtp_capabilities)).await?;
             let consumer_2 = consumer.clone();
             let consumer_id = consumer.id().to_string();
             let channel_id = channel.channel_id;
@@ -351,11 +1015,6 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
                         consumer_id: consumer_id_3.clone(),
                     }
                 });
-                _ = tx_3.send(IncomingMessage::MessageTo {
-                    channel: channel_id,
-                    peer: peer_id,
-                    message: ToClient::ConsumerClosed(consumer_id_3.clone())
-                });
             }).detach();
 
             let result = ToClient::ProducerConsumed {
@@ -367,85 +1026,152 @@ async fn process_client_command(channel: &mut Channel, peer_id: PeerID, message:
 
 
 
-            peer.consumers.insert(consumer_id, consumer);
+            peer.consumers.insert(consumer_id.clone(), consumer);
+            peer.transport_of.insert(consumer_id, consumer_transport_id);
 
-            if peer.deaf {
+            if !forward {
                 consumer_2.pause().await?;
             }
 
             result
         }
         FromClient::ConsumerClosed{consumer_id} => {
+            // The client is only told about the consumer closing if it was still open.
+            channel.remove_consumer(peer_id, &consumer_id, tx);
+            ToClient::Nothing
+        }
+        FromClient::GetProducers{kinds} => {
+            ToClient::NewProducers(channel.get_producers(peer_id, kinds.as_deref()))
+        }
+        FromClient::RaiseHand{raised} => {
             let Some(peer) = channel.peers.get_mut(&peer_id) else {
                 bail!("peer ID not found in channel");
             };
-            peer.producers.remove(&consumer_id);
-            ToClient::Nothing
+            if peer.role == PeerRole::Speaker && raised {
+                bail!("speakers have no need to raise their hand");
+            }
+            peer.hand_raised = raised;
+
+            // Everyone else in the channel gets to see it, moderators being peers as well.
+            _ = tx.send(IncomingMessage::BroadCast {
+                channel: channel.channel_id,
+                from_peer: peer_id,
+                message: ToClient::HandRaised { peer_id, raised },
+            });
+            ToClient::HandRaised { peer_id, raised }
         }
-        FromClient::GetProducers{} => {
-            ToClient::NewProducers(channel.get_producers())
+        FromClient::RequestMixedAudio{rtp_capabilities, consumer_transport_id} => {
+            channel.start_mixing(peer_id, consumer_transport_id, rtp_capabilities, tx).await?
+        }
+        FromClient::StopMixedAudio{} => {
+            let Some(peer) = channel.peers.get_mut(&peer_id) else {
+                bail!("peer ID not found in channel");
+            };
+            match peer.mix.take() {
+                Some(mix) => ToClient::ConsumerClosed(mix.consumer_id()),
+                None => ToClient::Nothing,
+            }
         }
     })
 }
 
 type ResponseSender = dyn futures_util::Sink<String, Error = anyhow::Error>
-                      + Unpin;
+                      + Unpin
+                      + Send;
 
 async fn process_command(state: &mut State,
                          message: IncomingMessage,
                          tx: &UnboundedSender<IncomingMessage>,
                          server_write: &mut ResponseSender) -> Result<()> {
     match message {
-        IncomingMessage::NewChannel{channel, codecs} => {
-            let opt = RouterOptions::new(codecs);
+        IncomingMessage::NewChannel{channel, codecs, profile, bitrate} => {
+            let codecs = match profile {
+                Some(profile) if codecs.is_empty() => profile.codecs(),
+                _ => codecs,
+            };
+            if codecs.is_empty() {
+                bail!("channel needs codecs or a profile");
+            }
+            let bitrate = bitrate
+                .or(profile.map(|p| p.bitrate_caps()))
+                .unwrap_or_default();
+            // Replacing a breakout room would strand its peers and confuse its parent.
+            if state.channels.get(&channel).is_some_and(|channel| channel.parent.is_some()) {
+                bail!("channel ID {} is taken by a breakout room", channel);
+            }
+            let opt = RouterOptions::new(codecs.clone());
             let router = state.worker.create_router(opt).await?; // TODO: This is a serious case...
-            state.channels.insert(channel, Channel {
-                channel_id: channel,
-                router,
-                peers: HashMap::new(),
-                announce_ip: state.announce_ip,
-                listen_ip: state.listen_ip,
-            });
+            state.channels.insert(channel, state.new_channel(channel, router, codecs, bitrate));
+        }
+        IncomingMessage::RemoveChannel{channel} => {
+            let Some(removed) = state.channels.get(&channel) else {
+                bail!("bad channel ID")
+            };
+            if removed.parent.is_some() {
+                bail!("breakout rooms are closed by ending the breakout");
+            }
+            for channel in state.breakout_family(channel) {
+                state.channels.remove(&channel);
+                state.moved.retain(|_, current| *current != channel);
+            }
         }
-        IncomingMessage::AddPeer{channel, peer} => {
+        IncomingMessage::AddPeer{channel, peer, role} => {
+            state.moved.remove(&(channel, peer));
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID")
             };
-            channel.peers.insert(peer, Peer {
-                deaf: false,
-                transports: HashMap::new(),
-                producers: HashMap::new(),
-                consumers: HashMap::new(),
-            });
+            channel.peers.insert(peer, Peer::new(role));
             _ = tx.send(IncomingMessage::MessageTo {
                 channel: channel.channel_id,
                 peer,
                 message: ToClient::Capabilities(channel.router.rtp_capabilities().clone())
             });
+            // Nobody cares about who the speakers are unless the channel is a stage.
+            if channel.has_listeners() {
+                _ = tx.send(IncomingMessage::MessageTo {
+                    channel: channel.channel_id,
+                    peer,
+                    message: ToClient::Speakers(channel.speakers()),
+                });
+            }
         }
         IncomingMessage::RemovePeer{channel, peer} => {
+            let channel = state.current_channel(channel, peer);
+            state.moved.retain(|(_, moved), current| !(*moved == peer && *current == channel));
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID")
             };
-            channel.peers.remove(&peer);
+            if !channel.remove_peer(peer, tx) {
+                bail!("bad peer ID");
+            }
+        }
+        IncomingMessage::AddRtpPeer{channel, peer, endpoint} => {
+            state.moved.remove(&(channel, peer));
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID")
+            };
+            channel.add_rtp_peer(peer, endpoint, tx).await?;
+        }
+        IncomingMessage::MovePeer{from, to, peer} => {
+            state.move_peer(from, to, peer, tx).await?;
         }
         IncomingMessage::RemoveTransport{channel, peer, transport_id} => {
+            let channel = state.current_channel(channel, peer);
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID");
             };
-            let Some(peer) = channel.peers.get_mut(&peer) else {
-                bail!("bad peer ID");
-            };
-            peer.transports.remove(&transport_id);
+            // The peer may already have been removed, taking the transport with it.
+            channel.remove_transport(peer, &transport_id, tx);
         }
         IncomingMessage::HandleClient{channel, peer, message} => {
+            let channel = state.current_channel(channel, peer);
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID")
             };
             match process_client_command(channel, peer, message, &tx).await? {
                 ToClient::Nothing => {},
                 m => {
-                    let wrapped = ToServer(channel.channel_id, peer, m);
+                    let wrapped = ToServer(channel.reported_id(), peer, m);
                     if let Err(e) = server_write.send(serde_json::to_string(&wrapped).unwrap().into()).await {
                         bail!("could not send to server: {}", e)
                     }
@@ -453,6 +1179,7 @@ async fn process_command(state: &mut State,
             }
         }
         IncomingMessage::SetDeafenPeer{channel, peer, deafen} => {
+            let channel = state.current_channel(channel, peer);
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID");
             };
@@ -460,22 +1187,164 @@ async fn process_command(state: &mut State,
                 bail!("bad peer ID");
             };
             peer.deaf = deafen;
-            for consumer in peer.consumers.values() {
-                // TODO: Do this in parallel for each producer and then join!
-                if deafen {
-                    consumer.pause().await?;
-                } else {
-                    consumer.resume().await?;
+            channel.sync_paused(tx).await?;
+        }
+        IncomingMessage::SetPeerRole{channel, peer, role} => {
+            let channel = state.current_channel(channel, peer);
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            let Some(peer_data) = channel.peers.get_mut(&peer) else {
+                bail!("bad peer ID");
+            };
+            peer_data.role = role;
+            peer_data.hand_raised = false;
+            if role == PeerRole::Listener {
+                let producers: Vec<String> = peer_data.producers.keys().cloned().collect();
+                for producer_id in producers {
+                    channel.remove_producer(peer, &producer_id, tx);
                 }
             }
+            _ = tx.send(IncomingMessage::BroadCast {
+                channel: channel.channel_id,
+                from_peer: CONTROLLER,
+                message: ToClient::Speakers(channel.speakers()),
+            });
         }
-        IncomingMessage::BroadCast{channel, from_peer, message} => {
+        IncomingMessage::SetLastN{channel, last_n} => {
             let Some(channel) = state.channels.get_mut(&channel) else {
                 bail!("bad channel ID");
             };
-            let mut m = ToServer(channel.channel_id, usize::MAX, message);
-            for peer in channel.peers.keys() {
-                if from_peer == *peer {
+            channel.set_last_n(last_n, tx).await?;
+        }
+        IncomingMessage::SetChannelBitrate{channel, bitrate} => {
+            if !state.channels.contains_key(&channel) {
+                bail!("bad channel ID");
+            }
+            // Replaces the caps as a whole, so leaving one out lifts it. The initial
+            // outgoing bitrate only matters for transports created from now on.
+            for room in state.breakout_family(channel) {
+                let Some(room) = state.channels.get_mut(&room) else {
+                    continue
+                };
+                room.bitrate = bitrate;
+                for peer in room.peers.values() {
+                    for transport in peer.transports.values() {
+                        profile::apply_bitrate_caps(transport, bitrate).await?;
+                    }
+                }
+            }
+        }
+        IncomingMessage::DominantSpeaker{channel, producer_id} => {
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                return Ok(())
+            };
+            channel.dominant_speaker(&producer_id, tx).await?;
+        }
+        IncomingMessage::SetPrioritySpeaker{channel, peer, priority} => {
+            let channel = state.current_channel(channel, peer);
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            channel.set_priority_speaker(peer, priority, tx).await?;
+        }
+        IncomingMessage::StartBreakout{channel, rooms, seconds} => {
+            state.start_breakout(channel, rooms, seconds, tx).await?;
+        }
+        IncomingMessage::AssignBreakout{channel, peer, room} => {
+            state.assign_breakout(channel, peer, room, tx).await?;
+        }
+        IncomingMessage::EndBreakout{channel} => {
+            state.end_breakout(channel, tx).await?;
+        }
+        IncomingMessage::BreakoutEnding{channel, seconds} => {
+            state.breakout_ending(channel, seconds, tx);
+        }
+        IncomingMessage::PriorityVolumes{channel, producer_ids} => {
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                return Ok(())
+            };
+            channel.priority_volumes(&producer_ids, tx).await?;
+        }
+        IncomingMessage::TraceProducer{channel, peer, producer_id, events, seconds, max_per_second} => {
+            let channel = state.current_channel(channel, peer);
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            let channel_id = channel.channel_id;
+            let Some(peer_data) = channel.peers.get_mut(&peer) else {
+                bail!("bad peer ID");
+            };
+            let Some(producer) = peer_data.producers.get(&producer_id) else {
+                bail!("bad producer ID");
+            };
+            let trace = trace::start_trace(producer, channel_id, peer, &events, seconds, max_per_second, tx).await?;
+            peer_data.traces.insert(producer_id, trace);
+        }
+        IncomingMessage::StopTrace{channel, peer, producer_id} => {
+            let channel = state.current_channel(channel, peer);
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            let Some(peer) = channel.peers.get_mut(&peer) else {
+                bail!("bad peer ID");
+            };
+            if peer.traces.remove(&producer_id).is_some() {
+                if let Some(producer) = peer.producers.get(&producer_id) {
+                    trace::stop_trace(producer).await?;
+                }
+            }
+        }
+        IncomingMessage::StartBroadcast{channel, address} => {
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            // Frees the address in case the broadcast is restarted on the same one.
+            channel.broadcast = None;
+            // No peer is the controller, so this is every audio producer.
+            let sources = channel.audio_producers_except(CONTROLLER);
+            channel.broadcast = Some(icecast::Broadcast::start(&channel.router, address, sources, channel.channel_id, tx).await?);
+        }
+        IncomingMessage::StopBroadcast{channel} => {
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            if channel.broadcast.take().is_some() {
+                _ = tx.send(IncomingMessage::MessageTo {
+                    channel: channel.channel_id,
+                    peer: CONTROLLER,
+                    message: ToClient::BroadcastStatus {
+                        live: false,
+                        address: None,
+                        listeners: 0,
+                    },
+                });
+            }
+        }
+        IncomingMessage::TraceExpired{channel, peer, producer_id} => {
+            let channel = state.current_channel(channel, peer);
+            let Some(peer) = state.channels.get_mut(&channel).and_then(|c| c.peers.get_mut(&peer)) else {
+                return Ok(())
+            };
+            // A newer trace of the same producer might have replaced the one that expired.
+            let expired = peer.traces.get(&producer_id)
+                .is_some_and(|t| t.deadline <= std::time::Instant::now());
+            if expired {
+                peer.traces.remove(&producer_id);
+                if let Some(producer) = peer.producers.get(&producer_id) {
+                    trace::stop_trace(producer).await?;
+                }
+            }
+        }
+        IncomingMessage::BroadCast{channel, from_peer, message} => {
+            // Breakout rooms may be gone by now, along with everyone to tell.
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                return Ok(())
+            };
+            let mut m = ToServer(channel.reported_id(), usize::MAX, message);
+            for (peer, peer_data) in &channel.peers {
+                // Dial-in peers have no client to tell.
+                if from_peer == *peer || peer_data.rtp.is_some() {
                     continue
                 }
                 m.1 = *peer; // Send the recipient peer ID.
@@ -485,16 +1354,30 @@ async fn process_command(state: &mut State,
             }
         }
         IncomingMessage::MessageTo{channel, peer, message} => {
-            let m = ToServer(channel, peer, message);
+            let m = ToServer(state.reported_channel(state.current_channel(channel, peer)), peer, message);
             if let Err(e) = server_write.send(serde_json::to_string(&m).unwrap()).await {
                 bail!("could not send to server: {}", e)
             }
         }
+        #[cfg(feature = "impairment")]
+        IncomingMessage::ImpairNetwork{channel, peer, transport_id, impairment} => {
+            let Some(channel) = state.channels.get_mut(&channel) else {
+                bail!("bad channel ID");
+            };
+            channel.impair(peer, transport_id, impairment, tx).await?;
+        }
+        IncomingMessage::Snapshot => {
+            let message = ToServer(NO_CHANNEL, CONTROLLER, ToClient::Snapshot(snapshot::Snapshot::of(state)));
+            server_write.send(serde_json::to_string(&message).unwrap()).await?;
+        }
+        IncomingMessage::ControllerClosed | IncomingMessage::WorkerDied{..} | IncomingMessage::ReportLoad | IncomingMessage::WriteSnapshot => {
+            // Handled by the run loop of Sfu.
+        }
         IncomingMessage::Heartbeat => {
             // Because tokio-tungstenite doesn't seem to be well written enough
             // to live off of just WS ping/pongs.
             if let Err(e) = server_write.send("heartbeat".to_string()).await {
-                println!("could not send heartbeat: {e}");
+                warn!("could not send heartbeat: {e}");
             }
         }
     }
@@ -531,113 +1414,130 @@ fn parse_log_tag(s: &str) -> Option<WorkerLogTag> {
     }
 }
 
+/// Sent in place of a WebSocket ping, which otherwise never reaches the SFU.
+const HEARTBEAT_REQUEST: &str = r#"{"type":"Heartbeat"}"#;
+
 // I have no idea why it wants 'static to be here. Frankly, I don't care.
-fn after_websocket_started<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static>(ws_stream: tokio_tungstenite::WebSocketStream<S>, tx: UnboundedSender<IncomingMessage>) -> Box<ResponseSender> {
+fn websocket_link<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static>(ws_stream: tokio_tungstenite::WebSocketStream<S>)
+    -> (impl futures_util::Sink<String, Error = anyhow::Error> + Unpin + Send + 'static, impl futures_util::Stream<Item = String> + Unpin + Send + 'static) {
     use futures_util::StreamExt;
+    use futures_util::future::ready;
+    use tokio_tungstenite::tungstenite::Message;
 
-    let (write, mut read) = ws_stream.split();
+    let (write, read) = ws_stream.split();
     let write_mapped = write
-        .with(|data| futures_util::future::ok(tokio_tungstenite::tungstenite::Message::text(data)))
+        .with(|data| futures_util::future::ok(Message::text(data)))
         .sink_map_err(|e: tokio_tungstenite::tungstenite::error::Error|anyhow::anyhow!(e));
-    tokio::spawn(async move {
-        while let Some(message) = read.next().await {
-            let message = match message {
-                Ok(message) => message,
-                Err(e) => { error!("failed to read message: {e}"); break }
-            };
-
-            if message.is_ping() {
-                _ = tx.send(IncomingMessage::Heartbeat);
-                continue
+    let read_mapped = read
+        .take_while(|message| {
+            if let Err(e) = message {
+                error!("failed to read message: {e}");
             }
+            ready(message.is_ok())
+        })
+        .filter_map(|message| ready(match message {
+            Ok(message) if message.is_ping() => Some(HEARTBEAT_REQUEST.to_string()),
+            Ok(Message::Text(msg)) => Some(msg),
+            _ => None,
+        }));
+
+    (write_mapped, Box::pin(read_mapped))
+}
 
-            if !message.is_text() {
-                continue
-            }
+async fn start_websocket(config: WorkerConfig) -> Result<()> {
+    let Ok(controller_url) = std::env::var("SFU_CONTROLLER_URL") else {
+        bail!("SFU_CONTROLLER_URL missing from env");
+    };
+    info!("controller URL: {}", &controller_url);
+    let url: controller_link::ControllerUrl = controller_url.parse()?;
+    let tls = controller_link::ControllerTls::from_env()?;
+    match remote::Registration::from_env(&config)? {
+        Some(registration) => remote::serve(config, &url, &tls, &registration).await,
+        None => controller_link::serve(config, &url, &tls, None).await,
+    }
+}
 
-            let tokio_tungstenite::tungstenite::Message::Text(msg) = message else { unreachable!(); };
-            let message = match serde_json::from_str::<IncomingMessage>(&msg) {
-                Ok(m) => m,
-                Err(e) => { error!("bad message: {} for input {}", e, msg); continue }
-            };
-            if let Err(e) = tx.send(message) {
-                error!("{}", e);
-                break
-            }
+/// Everything needed to start a media worker, normally read from the `SFU_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    pub log_level: WorkerLogLevel,
+    pub log_tags: Vec<WorkerLogTag>,
+    pub rtc_port_range: std::ops::RangeInclusive<u16>,
+    pub listen_ip: std::net::IpAddr,
+    pub announce_ip: std::net::IpAddr,
+    pub ice_servers: Vec<IceServer>,
+    pub worker_death: WorkerDeathPolicy,
+
+    /// How often to send the controller a `LoadReport`, if at all.
+    pub load_report_interval: Option<std::time::Duration>,
+
+    /// Where to keep a snapshot of the SFU state, if anywhere.
+    pub snapshot_file: Option<SnapshotFile>,
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> WorkerConfig {
+        let log_level = parse_log_level(&std::env::var("SFU_LOG_LEVEL").expect("SFU_LOG_LEVEL missing from env"));
+        let log_tags = std::env::var("SFU_LOG_TAGS")
+            .expect("SFU_LOG_TAGS missing from env")
+            .split(';')
+            .filter_map(parse_log_tag).collect();
+        let min_port = std::env::var("SFU_RTC_MIN_PORT").expect("SFU_RTC_MIN_PORT missing from env").parse().unwrap();
+        let max_port = std::env::var("SFU_RTC_MAX_PORT").expect("SFU_RTC_MAX_PORT missing from env").parse().unwrap();
+
+        let listen_ip = std::env::var("SFU_LISTEN_IP").expect("SFU_LISTEN_IP missing from env").parse().unwrap();
+        let announce_ip = std::env::var("SFU_ANNOUNCE_IP").expect("SFU_ANNOUNCE_IP missing from env").parse().expect("invalid IP for announce IP");
+
+        let worker_death = match std::env::var("SFU_ON_WORKER_DEATH").as_deref() {
+            Ok("recreate") => WorkerDeathPolicy::Recreate,
+            Ok("exit") | Ok("") | Err(_) => WorkerDeathPolicy::Exit,
+            Ok(policy) => panic!("unsupported SFU_ON_WORKER_DEATH: {policy}"),
+        };
+
+        let load_report_interval = match std::env::var("SFU_LOAD_REPORT_SECONDS") {
+            Ok(seconds) => Some(seconds.parse().expect("invalid SFU_LOAD_REPORT_SECONDS")),
+            Err(_) => Some(10),
+        }.filter(|seconds| *seconds > 0).map(std::time::Duration::from_secs);
+
+        WorkerConfig {
+            log_level,
+            log_tags,
+            rtc_port_range: std::ops::RangeInclusive::new(min_port, max_port),
+            listen_ip,
+            announce_ip,
+            ice_servers: turn::ice_servers_from_env(),
+            worker_death,
+            load_report_interval,
+            snapshot_file: SnapshotFile::from_env(),
         }
-        println!("read task closed");
-    });
+    }
+}
 
-    Box::new(write_mapped)
+/// Runs a media worker against an already established controller connection.
+/// Returns once the controller closes the connection.
+pub async fn run_worker<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static>(config: WorkerConfig, ws_stream: tokio_tungstenite::WebSocketStream<S>) -> Result<()> {
+    Sfu::builder(config).build().await?.attach_websocket(ws_stream).join().await
 }
 
-async fn start_websocket(tx: UnboundedSender<IncomingMessage>) -> Box<ResponseSender> {
-    let controller_url = std::env::var("SFU_CONTROLLER_URL")
-        .expect("SFU_CONTROLLER_URL missing from env");
-    println!("controller URL: {}", &controller_url);
-
-    if controller_url.starts_with("ws://unix/") {
-        // Assumed to be a Unix domain socket.
-
-        #[cfg(not(windows))]
-        {
-            // This is not a conventional way of encounting a HTTP url going over domain sockets.
-            // However, we start with the HTTP part, ignore the first colon (for protoco), and then once we reach the second colon, we find the
-            // file path of the domain socket.
-            let protocol = controller_url.find(':').expect("SFU_CONTROLLER_URL must contain two colons while in domain socket mode");
-            let colon = controller_url[protocol + 1..].find(':').expect("SFU_CONTROLLER_URL must contain two colons while in domain socket mode") + protocol + 1;
-            let http_path = &controller_url[0..colon];
-            let unix_path = &controller_url[colon + 1..];
-            let socket = tokio::net::UnixStream::connect(unix_path).await.unwrap();
-            let (ws_stream, _) = tokio_tungstenite::client_async(http_path, socket).await.expect("SFU controller connection failed (unix domain socket)");
-            return after_websocket_started(ws_stream, tx);
-        }
-
-        #[cfg(windows)]
-        panic!("domain socket support is disabled on win32");
-    } else {
-        // Do it over HTTP instead.
-        let url = url::Url::parse(&controller_url).unwrap();
-        let (ws_stream, _) = tokio_tungstenite::connect_async(url).await.expect("SFU controller connection failed (http over tcp)");
-        return after_websocket_started(ws_stream, tx);
-    }; 
+async fn start_standalone(config: WorkerConfig, address: &str) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    info!("serving browsers at: {}", address);
+    let sfu = Sfu::builder(config).build().await?;
+    standalone::serve(sfu, listener, standalone::StandaloneConfig::from_env()).await
 }
 
 pub async fn start_worker() {
     env_logger::init();
-    let manager = WorkerManager::new();
-
-    let mut worker_settings = WorkerSettings::default();
-    worker_settings.log_level = parse_log_level(&std::env::var("SFU_LOG_LEVEL").expect("SFU_LOG_LEVEL missing from env"));
-    worker_settings.log_tags = std::env::var("SFU_LOG_TAGS")
-        .expect("SFU_LOG_TAGS missing from env")
-        .split(';')
-        .filter_map(parse_log_tag).collect();
-    let min_port = std::env::var("SFU_RTC_MIN_PORT").expect("SFU_RTC_MIN_PORT missing from env").parse().unwrap();
-    let max_port = std::env::var("SFU_RTC_MAX_PORT").expect("SFU_RTC_MAX_PORT missing from env").parse().unwrap();
-    worker_settings.rtc_port_range = std::ops::RangeInclusive::new(min_port, max_port);
-
-    let listen_ip: std::net::IpAddr = std::env::var("SFU_LISTEN_IP").expect("SFU_LISTEN_IP missing from env").parse().unwrap();
-    let announce_ip: std::net::IpAddr = std::env::var("SFU_ANNOUNCE_IP").expect("SFU_ANNOUNCE_IP missing from env").parse().expect("invalid IP for announce IP");
-
-    let worker = manager.create_worker(worker_settings).await.expect("could not create SFU worker");
-
-    println!("starting media worker");
-
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-
-    let mut write = start_websocket(tx.clone()).await;
-
-    let mut state = State {
-        worker,
-        channels: HashMap::new(),
-        listen_ip,
-        announce_ip,
-    };
-
-    while let Some(message) = rx.recv().await {
-        if let Err(e) = process_command(&mut state, message, &tx, write.as_mut()).await {
-            error!("{}", e);
+    if let Ok(address) = std::env::var("SFU_STANDALONE_ADDRESS") {
+        if let Err(e) = start_standalone(WorkerConfig::from_env(), &address).await {
+            error!("could not serve browsers: {}", e);
+        }
+        return
+    }
+    if let Err(e) = start_websocket(WorkerConfig::from_env()).await {
+        error!("could not run SFU worker: {}", e);
+        if e.is::<WorkerDied>() {
+            std::process::exit(WORKER_DIED_EXIT_CODE);
         }
     }
     error!("connection to SFU controller ended unexpectedly");
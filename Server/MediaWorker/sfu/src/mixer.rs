@@ -0,0 +1,573 @@
+//! Server side mixing for peers that can not take one consumer per speaker. The Opus
+//! producers of a channel are consumed through a `DirectTransport`, decoded, mixed and
+//! encoded again, then handed to the peer as a single consumer.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::num::{NonZeroU32, NonZeroU8};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use log::warn;
+use mediasoup::prelude::*;
+use opus::{Application, Channels};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+use crate::{Channel, IncomingMessage, PeerID, ToClient};
+
+pub(crate) const SAMPLE_RATE: u32 = 48000;
+
+/// Samples in 20ms of mono audio.
+pub(crate) const FRAME: usize = 960;
+
+// Anything beyond 100ms behind is dropped instead of adding to the delay.
+const MAX_BUFFERED: usize = FRAME * 5;
+
+// Sources that went quiet for this long lose their decoder state.
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PAYLOAD_TYPE: u8 = 100;
+
+fn opus_codec_capability() -> RtpCodecCapability {
+    RtpCodecCapability::Audio {
+        mime_type: MimeTypeAudio::Opus,
+        preferred_payload_type: Some(PAYLOAD_TYPE),
+        clock_rate: NonZeroU32::new(SAMPLE_RATE).unwrap(),
+        channels: NonZeroU8::new(2).unwrap(),
+        parameters: RtpCodecParametersParameters::default(),
+        rtcp_feedback: vec![],
+    }
+}
+
+/// What the mixer claims to understand when consuming producers.
+pub(crate) fn opus_capabilities() -> RtpCapabilities {
+    RtpCapabilities {
+        codecs: vec![opus_codec_capability()],
+        header_extensions: vec![],
+    }
+}
+
+fn mixed_rtp_parameters(ssrc: u32) -> RtpParameters {
+    RtpParameters {
+        codecs: vec![RtpCodecParameters::Audio {
+            mime_type: MimeTypeAudio::Opus,
+            payload_type: PAYLOAD_TYPE,
+            clock_rate: NonZeroU32::new(SAMPLE_RATE).unwrap(),
+            channels: NonZeroU8::new(2).unwrap(),
+            parameters: RtpCodecParametersParameters::from([
+                ("useinbandfec", 1_u32.into()),
+            ]),
+            rtcp_feedback: vec![],
+        }],
+        encodings: vec![RtpEncodingParameters {
+            ssrc: Some(ssrc),
+            ..RtpEncodingParameters::default()
+        }],
+        ..RtpParameters::default()
+    }
+}
+
+/// Skips the RTP header, CSRCs, header extension and padding.
+fn rtp_payload(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None
+    }
+    let mut start = 12 + 4 * (packet[0] & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        let header = packet.get(start..start + 4)?;
+        start += 4 + 4 * u16::from_be_bytes([header[2], header[3]]) as usize;
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    packet.get(start..end)
+}
+
+pub(crate) fn random_ssrc() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+struct Source {
+    decoder: opus::Decoder,
+    pcm: VecDeque<i16>,
+    last_packet: Instant,
+}
+
+/// Decodes Opus from any number of sources and mixes them into one mono stream.
+pub(crate) struct Mixer {
+    sources: HashMap<String, Source>,
+    decoded: Vec<i16>,
+}
+
+impl Mixer {
+    pub(crate) fn new() -> Mixer {
+        Mixer {
+            sources: HashMap::new(),
+            // Room for the longest packet Opus allows, 120ms.
+            decoded: vec![0; FRAME * 6],
+        }
+    }
+
+    pub(crate) fn push(&mut self, source: &str, packet: &[u8]) -> Result<()> {
+        let Some(payload) = rtp_payload(packet) else {
+            bail!("malformed RTP packet");
+        };
+        if !self.sources.contains_key(source) {
+            self.sources.insert(source.to_string(), Source {
+                decoder: opus::Decoder::new(SAMPLE_RATE, Channels::Mono)?,
+                pcm: VecDeque::new(),
+                last_packet: Instant::now(),
+            });
+        }
+        let Some(source) = self.sources.get_mut(source) else {
+            unreachable!();
+        };
+        let samples = source.decoder.decode(payload, &mut self.decoded, false)?;
+        source.pcm.extend(&self.decoded[..samples]);
+        let excess = source.pcm.len().saturating_sub(MAX_BUFFERED);
+        source.pcm.drain(..excess);
+        source.last_packet = Instant::now();
+        Ok(())
+    }
+
+    /// The next 20ms of the mix, or None if nobody is talking.
+    pub(crate) fn next_frame(&mut self) -> Option<Vec<i16>> {
+        self.sources.retain(|_, source| source.last_packet.elapsed() < SOURCE_TIMEOUT);
+        let mut mixed = vec![0_i32; FRAME];
+        let mut any = false;
+        for source in self.sources.values_mut() {
+            let n = source.pcm.len().min(FRAME);
+            for (sample, mixed) in source.pcm.drain(..n).zip(mixed.iter_mut()) {
+                *mixed += sample as i32;
+            }
+            any |= n > 0;
+        }
+        any.then(|| mixed.into_iter()
+            .map(|sample| sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+            .collect())
+    }
+}
+
+/// Turns encoded frames back into RTP packets.
+pub(crate) struct Packetizer {
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    marker: bool,
+}
+
+impl Packetizer {
+    pub(crate) fn new(ssrc: u32) -> Packetizer {
+        Packetizer {
+            ssrc,
+            sequence: 0,
+            timestamp: 0,
+            marker: true,
+        }
+    }
+
+    pub(crate) fn packet(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80);
+        packet.push(PAYLOAD_TYPE | if self.marker { 0x80 } else { 0 });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(FRAME as u32);
+        self.marker = false;
+        packet
+    }
+
+    /// Lets time pass without sending anything, like Opus DTX does.
+    pub(crate) fn skip(&mut self) {
+        self.timestamp = self.timestamp.wrapping_add(FRAME as u32);
+        self.marker = true;
+    }
+}
+
+pub(crate) struct SourcePacket {
+    pub(crate) source: String,
+    pub(crate) packet: Vec<u8>,
+}
+
+/// Producers consumed through a direct transport, their RTP ending up in one queue.
+pub(crate) struct Inputs {
+    pub(crate) transport: DirectTransport,
+    consumers: HashMap<String, Consumer>,
+    packets: UnboundedSender<SourcePacket>,
+}
+
+impl Inputs {
+    pub(crate) async fn new(router: &Router) -> Result<(Inputs, UnboundedReceiver<SourcePacket>)> {
+        let transport = router.create_direct_transport(DirectTransportOptions::default()).await?;
+        let (packets, rx) = unbounded_channel();
+        Ok((Inputs {
+            transport,
+            consumers: HashMap::new(),
+            packets,
+        }, rx))
+    }
+
+    pub(crate) async fn add(&mut self, producer_id: ProducerId) -> Result<()> {
+        let source = producer_id.to_string();
+        if self.consumers.contains_key(&source) {
+            return Ok(())
+        }
+        let consumer = self.transport.consume(ConsumerOptions::new(producer_id, opus_capabilities())).await?;
+        let packets = self.packets.clone();
+        let source_2 = source.clone();
+        consumer.on_rtp(move |packet| {
+            _ = packets.send(SourcePacket {
+                source: source_2.clone(),
+                packet: packet.to_vec(),
+            });
+        }).detach();
+        self.consumers.insert(source, consumer);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, producer_id: &str) {
+        self.consumers.remove(producer_id);
+    }
+}
+
+async fn mix_into(mut packets: UnboundedReceiver<SourcePacket>, producer: DirectProducer, ssrc: u32) {
+    let mut mixer = Mixer::new();
+    let mut encoder = match opus::Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            warn!("could not create Opus encoder: {e}");
+            return
+        }
+    };
+    let mut packetizer = Packetizer::new(ssrc);
+    let mut encoded = vec![0; 4000];
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    // Catching up on missed ticks would send a burst of frames the peer has to buffer.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    return
+                };
+                // A single broken packet is not worth giving up the mix for.
+                _ = mixer.push(&packet.source, &packet.packet);
+            }
+            _ = interval.tick() => {
+                let Some(frame) = mixer.next_frame() else {
+                    packetizer.skip();
+                    continue
+                };
+                match encoder.encode(&frame, &mut encoded) {
+                    Ok(n) => {
+                        _ = producer.send(packetizer.packet(&encoded[..n]));
+                    }
+                    Err(e) => warn!("could not encode mixed audio: {e}"),
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct MixedAudio {
+    inputs: Inputs,
+    consumer: Consumer,
+
+    // The transport of the peer that the mix is delivered on.
+    pub(crate) transport_id: String,
+
+    // Keeps the mixed producer alive.
+    _producer: Producer,
+    task: JoinHandle<()>,
+}
+
+impl Drop for MixedAudio {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl MixedAudio {
+    /// Mixes the producers on the router and delivers the mix through a consumer on the transport.
+    pub(crate) async fn start<T: Transport>(router: &Router,
+                                            sources: Vec<ProducerId>,
+                                            transport: &T,
+                                            rtp_capabilities: RtpCapabilities,
+                                            paused: bool) -> Result<MixedAudio> {
+        let (mut inputs, packets) = Inputs::new(router).await?;
+        for producer_id in sources {
+            inputs.add(producer_id).await?;
+        }
+
+        let ssrc = random_ssrc();
+        let producer = inputs.transport.produce(ProducerOptions::new(MediaKind::Audio, mixed_rtp_parameters(ssrc))).await?;
+        let Producer::Direct(direct) = producer.clone() else {
+            bail!("direct transport gave a regular producer");
+        };
+        if !router.can_consume(&producer.id(), &rtp_capabilities) {
+            bail!("router can not consume provided capabilities");
+        }
+        let consumer = transport.consume(ConsumerOptions::new(producer.id(), rtp_capabilities)).await?;
+        if paused {
+            consumer.pause().await?;
+        }
+        Ok(MixedAudio {
+            inputs,
+            consumer,
+            transport_id: transport.id().to_string(),
+            _producer: producer,
+            task: tokio::spawn(mix_into(packets, direct, ssrc)),
+        })
+    }
+
+    pub(crate) fn consumer_id(&self) -> String {
+        self.consumer.id().to_string()
+    }
+
+    pub(crate) fn producer_id(&self) -> String {
+        self.consumer.producer_id().to_string()
+    }
+
+    pub(crate) fn rtp_parameters(&self) -> RtpParameters {
+        self.consumer.rtp_parameters().clone()
+    }
+
+    pub(crate) async fn set_paused(&self, paused: bool) -> Result<()> {
+        if paused && !self.consumer.paused() {
+            self.consumer.pause().await?;
+        } else if !paused && self.consumer.paused() {
+            self.consumer.resume().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Channel {
+    pub(crate) fn audio_producers_except(&self, peer_id: PeerID) -> Vec<ProducerId> {
+        self.peers.iter()
+            .filter(|(id, _)| **id != peer_id)
+            .flat_map(|(_, peer)| peer.producers.values())
+            .filter(|producer| producer.kind() == MediaKind::Audio)
+            .filter(|producer| self.may_consume(peer_id, &producer.id().to_string()))
+            .map(|producer| producer.id())
+            .collect()
+    }
+
+    /// Whether the peer gets the producer as part of its mix instead of consuming it.
+    pub(crate) fn is_mixed_for(&self, peer_id: PeerID, producer_id: &str) -> bool {
+        let mixing = self.peers.get(&peer_id).is_some_and(|peer| peer.mix.is_some());
+        mixing && self.peers.values()
+            .filter_map(|peer| peer.producers.get(producer_id))
+            .any(|producer| producer.kind() == MediaKind::Audio)
+    }
+
+    /// Replaces the audio consumers of a peer with a single consumer of everyone else mixed.
+    pub(crate) async fn start_mixing(&mut self,
+                                     peer_id: PeerID,
+                                     transport_id: String,
+                                     rtp_capabilities: RtpCapabilities,
+                                     tx: &UnboundedSender<IncomingMessage>) -> Result<ToClient> {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            bail!("peer ID not found in channel");
+        };
+        if !peer.transports.contains_key(&transport_id) {
+            bail!("transport ID not found in peer");
+        }
+        peer.mix = None;
+        let audio: Vec<String> = peer.consumers.iter()
+            .filter(|(_, consumer)| consumer.kind() == MediaKind::Audio)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for consumer_id in audio {
+            self.remove_consumer(peer_id, &consumer_id, tx);
+        }
+
+        let sources = self.audio_producers_except(peer_id);
+        for producer_id in &sources {
+            self.pipe_to_peer(*producer_id, peer_id).await?;
+        }
+        let Some(peer) = self.peers.get(&peer_id) else {
+            bail!("peer ID not found in channel");
+        };
+        let Some(transport) = peer.transports.get(&transport_id) else {
+            bail!("transport ID not found in peer");
+        };
+        let mix = MixedAudio::start(self.router_of(peer), sources, transport, rtp_capabilities, peer.deaf).await?;
+        let result = ToClient::MixedAudio {
+            id: mix.consumer_id(),
+            producer_id: mix.producer_id(),
+            rtp_parameters: mix.rtp_parameters(),
+        };
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.mix = Some(mix);
+        }
+        Ok(result)
+    }
+
+    /// Adds a new producer to the broadcast and to the mix of every mixing peer but its owner,
+    /// leaving out whoever a whisper is not meant for.
+    pub(crate) async fn mix_producer(&mut self, producer: &Producer, owner: PeerID) -> Result<()> {
+        if producer.kind() != MediaKind::Audio {
+            return Ok(())
+        }
+        let producer_id = producer.id().to_string();
+        if self.audience_of(&producer_id).is_none() {
+            if let Some(broadcast) = &mut self.broadcast {
+                broadcast.add(producer.id()).await?;
+            }
+        }
+        let mixing: Vec<PeerID> = self.peers.iter()
+            .filter(|(id, peer)| **id != owner && peer.mix.is_some() && self.may_consume(**id, &producer_id))
+            .map(|(id, _)| *id)
+            .collect();
+        for peer_id in mixing {
+            self.pipe_to_peer(producer.id(), peer_id).await?;
+            if let Some(mix) = self.peers.get_mut(&peer_id).and_then(|peer| peer.mix.as_mut()) {
+                mix.inputs.add(producer.id()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unmix_producer(&mut self, producer_id: &str) {
+        if let Some(broadcast) = &mut self.broadcast {
+            broadcast.remove(producer_id);
+        }
+        for peer in self.peers.values_mut() {
+            if let Some(mix) = &mut peer.mix {
+                mix.inputs.remove(producer_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(pcm: Vec<i16>) -> Source {
+        Source {
+            decoder: opus::Decoder::new(SAMPLE_RATE, Channels::Mono).unwrap(),
+            pcm: pcm.into(),
+            last_packet: Instant::now(),
+        }
+    }
+
+    fn mixer(sources: Vec<Vec<i16>>) -> Mixer {
+        let mut mixer = Mixer::new();
+        for (i, pcm) in sources.into_iter().enumerate() {
+            mixer.sources.insert(i.to_string(), source(pcm));
+        }
+        mixer
+    }
+
+    #[test]
+    fn rtp_payload_skips_headers_and_padding() {
+        let mut packet = vec![0x80, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(b"opus");
+        assert_eq!(rtp_payload(&packet), Some(&b"opus"[..]));
+
+        // Two CSRCs and a one word header extension.
+        let mut packet = vec![0x92, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 0x10, 0xff, 0, 0]);
+        packet.extend_from_slice(b"opus");
+        assert_eq!(rtp_payload(&packet), Some(&b"opus"[..]));
+
+        // Three bytes of padding, the last one counting them.
+        let mut packet = vec![0xa0, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(b"opus");
+        packet.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(rtp_payload(&packet), Some(&b"opus"[..]));
+    }
+
+    #[test]
+    fn rtp_payload_refuses_malformed_packets() {
+        assert_eq!(rtp_payload(&[0x80, 100, 0, 1]), None);
+        // Not RTP version 2.
+        assert_eq!(rtp_payload(&[0x40, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1]), None);
+        // The header extension runs past the end.
+        assert_eq!(rtp_payload(&[0x90, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0xbe, 0xde, 0, 4]), None);
+        // More padding than packet.
+        assert_eq!(rtp_payload(&[0xa0, 100, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 200]), None);
+    }
+
+    #[test]
+    fn packetizer_counts_sequence_numbers_and_timestamps() {
+        let mut packetizer = Packetizer::new(0x01020304);
+        let first = packetizer.packet(b"a");
+        assert_eq!(first, [0x80, 0x80 | PAYLOAD_TYPE, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, b'a']);
+        assert_eq!(rtp_payload(&first), Some(&b"a"[..]));
+
+        let second = packetizer.packet(b"b");
+        assert_eq!(second[1], PAYLOAD_TYPE);
+        assert_eq!(second[2..4], 1_u16.to_be_bytes());
+        assert_eq!(second[4..8], (FRAME as u32).to_be_bytes());
+
+        // Skipped frames advance the clock but not the sequence, and mark the next talkspurt.
+        packetizer.skip();
+        let third = packetizer.packet(b"c");
+        assert_eq!(third[1], 0x80 | PAYLOAD_TYPE);
+        assert_eq!(third[2..4], 2_u16.to_be_bytes());
+        assert_eq!(third[4..8], (FRAME as u32 * 3).to_be_bytes());
+    }
+
+    #[test]
+    fn packetizer_wraps_around() {
+        let mut packetizer = Packetizer::new(1);
+        packetizer.sequence = u16::MAX;
+        packetizer.timestamp = u32::MAX;
+        packetizer.packet(b"a");
+        let packet = packetizer.packet(b"b");
+        assert_eq!(packet[2..4], 0_u16.to_be_bytes());
+        assert_eq!(packet[4..8], (FRAME as u32 - 1).to_be_bytes());
+    }
+
+    #[test]
+    fn mixer_sums_sources() {
+        let mut mixer = mixer(vec![vec![100; FRAME], vec![-300; FRAME / 2]]);
+        let frame = mixer.next_frame().unwrap();
+        assert_eq!(frame.len(), FRAME);
+        assert!(frame[..FRAME / 2].iter().all(|sample| *sample == -200));
+        assert!(frame[FRAME / 2..].iter().all(|sample| *sample == 100));
+        assert_eq!(mixer.next_frame(), None);
+    }
+
+    #[test]
+    fn mixer_clips_instead_of_wrapping() {
+        let mut mixer = mixer(vec![vec![30000, -30000], vec![30000, -30000]]);
+        let frame = mixer.next_frame().unwrap();
+        assert_eq!(frame[..2], [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn mixer_decodes_pushed_packets() {
+        let mut encoder = opus::Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip).unwrap();
+        let mut encoded = vec![0; 4000];
+        let n = encoder.encode(&[0; FRAME], &mut encoded).unwrap();
+        let packet = Packetizer::new(1).packet(&encoded[..n]);
+
+        let mut mixer = Mixer::new();
+        assert!(mixer.push("a", &packet[..4]).is_err());
+        mixer.push("a", &packet).unwrap();
+        assert_eq!(mixer.next_frame().map(|frame| frame.len()), Some(FRAME));
+        assert_eq!(mixer.next_frame(), None);
+    }
+
+    #[test]
+    fn mixer_forgets_quiet_sources() {
+        let mut mixer = mixer(vec![vec![100; FRAME]]);
+        for source in mixer.sources.values_mut() {
+            source.last_packet -= SOURCE_TIMEOUT;
+        }
+        assert_eq!(mixer.next_frame(), None);
+        assert!(mixer.sources.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Moves peers between channels of the same worker without tearing down their
+//! transports. A transport can not change router, so the peer keeps using the router
+//! it first joined through and producers are piped between that router and the router
+//! of the channel it is currently in.
+
+use anyhow::{bail, Result};
+use mediasoup::prelude::*;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{Channel, IncomingMessage, Peer, PeerID, State, ToClient};
+
+impl Channel {
+    /// The router that the transports of the peer live on.
+    pub(crate) fn router_of<'a>(&'a self, peer: &'a Peer) -> &'a Router {
+        peer.transport_router.as_ref().unwrap_or(&self.router)
+    }
+
+    /// Makes a producer of this channel available on the router of a peer that moved here.
+    pub(crate) async fn pipe_to_peer(&mut self, producer_id: ProducerId, peer_id: PeerID) -> Result<()> {
+        let Some(peer) = self.peers.get(&peer_id) else {
+            bail!("peer ID not found in channel");
+        };
+        let Some(target) = peer.transport_router.clone() else {
+            return Ok(())
+        };
+        let Some(owner) = self.owner_of(&producer_id.to_string()).and_then(|owner| self.peers.get(&owner)) else {
+            bail!("producer ID not found in channel");
+        };
+        let source = self.router_of(owner).clone();
+        let key = (producer_id.to_string(), target.id());
+        if source.id() == target.id() || self.pipes.contains_key(&key) {
+            return Ok(())
+        }
+        let pipe = source.pipe_producer_to_router(producer_id, PipeToRouterOptions::new(target)).await?;
+        self.pipes.insert(key, pipe);
+        Ok(())
+    }
+
+    /// Makes a producer of a peer that moved here available on the router of this channel.
+    pub(crate) async fn pipe_from_peer(&mut self, producer: &Producer, peer_id: PeerID) -> Result<()> {
+        let Some(source) = self.peers.get(&peer_id).and_then(|peer| peer.transport_router.clone()) else {
+            return Ok(())
+        };
+        let pipe = source.pipe_producer_to_router(producer.id(), PipeToRouterOptions::new(self.router.clone())).await?;
+        self.pipes.insert((producer.id().to_string(), self.router.id()), pipe);
+        Ok(())
+    }
+}
+
+impl State {
+    /// Which channel a peer is in, given the channel it was in when a handler was registered.
+    pub(crate) fn current_channel(&self, channel: usize, peer: PeerID) -> usize {
+        self.moved.get(&(channel, peer)).copied().unwrap_or(channel)
+    }
+
+    /// The channel the controller knows a channel by, breakout rooms going by their parent.
+    pub(crate) fn reported_channel(&self, channel: usize) -> usize {
+        self.channels.get(&channel).map_or(channel, Channel::reported_id)
+    }
+
+    pub(crate) async fn move_peer(&mut self, from: usize, to: usize, peer_id: PeerID, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        if from == to {
+            bail!("peer is already in the channel");
+        }
+        let Some(to_channel) = self.channels.get(&to) else {
+            bail!("bad channel ID");
+        };
+        if to_channel.peers.contains_key(&peer_id) {
+            bail!("peer is already in the channel");
+        }
+        let is_rtp = self.channels.get(&from)
+            .and_then(|channel| channel.peers.get(&peer_id))
+            .is_some_and(|peer| peer.rtp.is_some());
+        if is_rtp {
+            bail!("RTP peers can not move");
+        }
+        let Some(from_channel) = self.channels.get_mut(&from) else {
+            bail!("bad channel ID");
+        };
+        let Some(mut peer) = from_channel.take_peer(peer_id, tx) else {
+            bail!("bad peer ID");
+        };
+        let router = peer.transport_router.take().unwrap_or_else(|| from_channel.router.clone());
+
+        // Whatever the peer was consuming belongs to the channel it left.
+        for consumer_id in peer.consumers.keys() {
+            peer.transport_of.remove(consumer_id);
+            _ = tx.send(IncomingMessage::MessageTo {
+                channel: to,
+                peer: peer_id,
+                message: ToClient::ConsumerClosed(consumer_id.clone()),
+            });
+        }
+        peer.consumers.clear();
+        if let Some(mix) = peer.mix.take() {
+            _ = tx.send(IncomingMessage::MessageTo {
+                channel: to,
+                peer: peer_id,
+                message: ToClient::ConsumerClosed(mix.consumer_id()),
+            });
+        }
+
+        let Some(to_channel) = self.channels.get_mut(&to) else {
+            unreachable!();
+        };
+        if router.id() != to_channel.router.id() {
+            peer.transport_router = Some(router.clone());
+        }
+        let producers: Vec<Producer> = peer.producers.values().cloned().collect();
+        to_channel.peers.insert(peer_id, peer);
+        for producer in &producers {
+            to_channel.pipe_from_peer(producer, peer_id).await?;
+            to_channel.observe_producer(producer).await?;
+            to_channel.mix_producer(producer, peer_id).await?;
+        }
+
+        _ = tx.send(IncomingMessage::MessageTo {
+            channel: to,
+            peer: peer_id,
+            message: ToClient::Moved {
+                channel: self.reported_channel(to),
+                capabilities: router.rtp_capabilities().clone(),
+            },
+        });
+        let others = to_channel.get_producers(peer_id, None)
+            .into_iter()
+            .filter(|producer| producer.peer_id != peer_id)
+            .collect();
+        _ = tx.send(IncomingMessage::MessageTo {
+            channel: to,
+            peer: peer_id,
+            message: ToClient::NewProducers(others),
+        });
+        let producer_ids: Vec<String> = producers.iter().map(|producer| producer.id().to_string()).collect();
+        to_channel.announce_producers(peer_id, &producer_ids, tx);
+
+        // Handlers registered before the move still think the peer is in an older channel.
+        self.moved.remove(&(to, peer_id));
+        for current in self.moved.iter_mut().filter(|((_, peer), _)| *peer == peer_id).map(|(_, current)| current) {
+            if *current == from {
+                *current = to;
+            }
+        }
+        self.moved.insert((from, peer_id), to);
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Priority speakers: moderators whose voice has to carry over the crowd. While one of
+//! them is louder than a threshold everyone is told to duck the rest of the channel,
+//! and under last-N the priority speakers take the first forwarded slots.
+
+use std::num::NonZeroU16;
+
+use anyhow::{bail, Result};
+use mediasoup::audio_level_observer::{AudioLevelObserver, AudioLevelObserverOptions, AudioLevelObserverVolume};
+use mediasoup::prelude::*;
+use mediasoup::rtp_observer::{RtpObserver, RtpObserverAddProducerOptions};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{Channel, IncomingMessage, PeerID, ToClient, CONTROLLER};
+
+// In dBov, where 0 is the loudest.
+const THRESHOLD: i8 = -50;
+const INTERVAL_MS: u16 = 300;
+
+// How loud the rest of the channel should be while a priority speaker talks.
+const DUCK_FACTOR: f32 = 0.3;
+
+pub(crate) struct Priority {
+    observer: AudioLevelObserver,
+
+    // The priority speakers currently above the threshold.
+    speaking: Vec<PeerID>,
+}
+
+impl Channel {
+    pub(crate) fn priority_speaking(&self) -> &[PeerID] {
+        self.priority.as_ref().map_or(&[], |priority| priority.speaking.as_slice())
+    }
+
+    async fn start_priority(&mut self, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let mut options = AudioLevelObserverOptions::default();
+        options.max_entries = NonZeroU16::new(4).unwrap();
+        options.threshold = THRESHOLD;
+        options.interval = INTERVAL_MS;
+        let observer = self.router.create_audio_level_observer(options).await?;
+
+        let channel_id = self.channel_id;
+        let tx_2 = tx.clone();
+        observer.on_volumes(move |volumes: &Vec<AudioLevelObserverVolume>| {
+            _ = tx_2.send(IncomingMessage::PriorityVolumes {
+                channel: channel_id,
+                producer_ids: volumes.iter().map(|volume| volume.producer.id().to_string()).collect(),
+            });
+        }).detach();
+        let tx_3 = tx.clone();
+        observer.on_silence(move || {
+            _ = tx_3.send(IncomingMessage::PriorityVolumes {
+                channel: channel_id,
+                producer_ids: vec![],
+            });
+        }).detach();
+        self.priority = Some(Priority {
+            observer,
+            speaking: vec![],
+        });
+        Ok(())
+    }
+
+    /// Makes a peer a priority speaker, or an ordinary one again.
+    pub(crate) async fn set_priority_speaker(&mut self, peer_id: PeerID, priority: bool, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            bail!("bad peer ID");
+        };
+        if peer.priority == priority {
+            return Ok(())
+        }
+        peer.priority = priority;
+        let audio: Vec<ProducerId> = peer.producers.values()
+            .filter(|producer| producer.kind() == MediaKind::Audio)
+            .map(|producer| producer.id())
+            .collect();
+
+        if self.priority.is_none() {
+            if !priority {
+                return Ok(())
+            }
+            self.start_priority(tx).await?;
+        }
+        let Some(state) = &self.priority else {
+            unreachable!();
+        };
+        for producer_id in audio {
+            if priority {
+                state.observer.add_producer(RtpObserverAddProducerOptions::new(producer_id)).await?;
+            } else {
+                state.observer.remove_producer(producer_id).await?;
+            }
+        }
+        if !priority && state.speaking.contains(&peer_id) {
+            let still: Vec<PeerID> = state.speaking.iter().copied().filter(|speaker| *speaker != peer_id).collect();
+            self.set_priority_speaking(still, tx).await?;
+        }
+        Ok(())
+    }
+
+    /// Lets the audio level observer know about a new producer of a priority speaker.
+    pub(crate) async fn observe_priority(&self, producer: &Producer) -> Result<()> {
+        let Some(state) = &self.priority else {
+            return Ok(())
+        };
+        let owner = self.owner_of(&producer.id().to_string()).and_then(|owner| self.peers.get(&owner));
+        if producer.kind() == MediaKind::Audio && owner.is_some_and(|peer| peer.priority) {
+            state.observer.add_producer(RtpObserverAddProducerOptions::new(producer.id())).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles the producers the audio level observer found above the threshold.
+    pub(crate) async fn priority_volumes(&mut self, producer_ids: &[String], tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let mut speaking = vec![];
+        for producer_id in producer_ids {
+            let Some(owner) = self.owner_of(producer_id) else {
+                continue
+            };
+            if self.peers.get(&owner).is_some_and(|peer| peer.priority) && !speaking.contains(&owner) {
+                speaking.push(owner);
+            }
+        }
+        self.set_priority_speaking(speaking, tx).await
+    }
+
+    async fn set_priority_speaking(&mut self, speaking: Vec<PeerID>, tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(state) = &mut self.priority else {
+            return Ok(())
+        };
+        if state.speaking == speaking {
+            return Ok(())
+        }
+        let factor = if speaking.is_empty() { 1.0 } else { DUCK_FACTOR };
+        state.speaking = speaking.clone();
+        _ = tx.send(IncomingMessage::BroadCast {
+            channel: self.channel_id,
+            from_peer: CONTROLLER,
+            message: ToClient::Duck {
+                factor,
+                speakers: speaking,
+            },
+        });
+        self.sync_paused(tx).await
+    }
+}
@@ -0,0 +1,121 @@
+//! Named presets for what a channel is used for, so that the controller does not need
+//! to spell out codecs and bitrates for every channel it creates.
+
+use std::num::{NonZeroU32, NonZeroU8};
+
+use anyhow::Result;
+use mediasoup::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ChannelProfile {
+    // Opus mono at 32 kbps with DTX and FEC.
+    Voice,
+
+    // Opus stereo at 128 kbps.
+    Music,
+
+    // Opus together with VP8 and H264.
+    Video,
+}
+
+/// In bits per second. Missing or null values are not capped, which for the initial
+/// outgoing bitrate means mediasoup picks one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BitrateCaps {
+    pub(crate) max_incoming_bitrate: Option<u32>,
+    pub(crate) max_outgoing_bitrate: Option<u32>,
+    pub(crate) initial_available_outgoing_bitrate: Option<u32>,
+}
+
+fn opus(parameters: RtpCodecParametersParameters) -> RtpCodecCapability {
+    RtpCodecCapability::Audio {
+        mime_type: MimeTypeAudio::Opus,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(48000).unwrap(),
+        // Opus always claims two channels, mono is signaled through the parameters.
+        channels: NonZeroU8::new(2).unwrap(),
+        parameters,
+        rtcp_feedback: vec![RtcpFeedback::TransportCc],
+    }
+}
+
+fn video(mime_type: MimeTypeVideo, parameters: RtpCodecParametersParameters) -> RtpCodecCapability {
+    RtpCodecCapability::Video {
+        mime_type,
+        preferred_payload_type: None,
+        clock_rate: NonZeroU32::new(90000).unwrap(),
+        parameters,
+        rtcp_feedback: vec![
+            RtcpFeedback::Nack,
+            RtcpFeedback::NackPli,
+            RtcpFeedback::CcmFir,
+            RtcpFeedback::GoogRemb,
+            RtcpFeedback::TransportCc,
+        ],
+    }
+}
+
+impl ChannelProfile {
+    pub(crate) fn codecs(self) -> Vec<RtpCodecCapability> {
+        match self {
+            ChannelProfile::Voice => vec![
+                opus(RtpCodecParametersParameters::from([
+                    ("stereo", 0_u32.into()),
+                    ("useinbandfec", 1_u32.into()),
+                    ("usedtx", 1_u32.into()),
+                    ("maxaveragebitrate", 32000_u32.into()),
+                ])),
+            ],
+            ChannelProfile::Music => vec![
+                opus(RtpCodecParametersParameters::from([
+                    ("stereo", 1_u32.into()),
+                    ("sprop-stereo", 1_u32.into()),
+                    ("useinbandfec", 1_u32.into()),
+                    ("maxaveragebitrate", 128000_u32.into()),
+                ])),
+            ],
+            ChannelProfile::Video => vec![
+                opus(RtpCodecParametersParameters::from([
+                    ("useinbandfec", 1_u32.into()),
+                ])),
+                video(MimeTypeVideo::Vp8, RtpCodecParametersParameters::default()),
+                video(MimeTypeVideo::H264, RtpCodecParametersParameters::from([
+                    ("packetization-mode", 1_u32.into()),
+                    ("level-asymmetry-allowed", 1_u32.into()),
+                    ("profile-level-id", "42e01f".into()),
+                ])),
+            ],
+        }
+    }
+
+    pub(crate) fn bitrate_caps(self) -> BitrateCaps {
+        match self {
+            ChannelProfile::Voice => BitrateCaps {
+                max_incoming_bitrate: Some(64_000),
+                max_outgoing_bitrate: None,
+                initial_available_outgoing_bitrate: Some(300_000),
+            },
+            ChannelProfile::Music => BitrateCaps {
+                max_incoming_bitrate: Some(192_000),
+                max_outgoing_bitrate: None,
+                initial_available_outgoing_bitrate: Some(600_000),
+            },
+            ChannelProfile::Video => BitrateCaps {
+                max_incoming_bitrate: Some(1_500_000),
+                max_outgoing_bitrate: None,
+                initial_available_outgoing_bitrate: Some(1_000_000),
+            },
+        }
+    }
+}
+
+/// Applies the caps that can be changed on a transport after it has been created.
+/// Missing caps are lifted, which mediasoup does for a bitrate of 0.
+pub(crate) async fn apply_bitrate_caps(transport: &WebRtcTransport, caps: BitrateCaps) -> Result<()> {
+    transport.set_max_incoming_bitrate(caps.max_incoming_bitrate.unwrap_or(0)).await?;
+    transport.set_max_outgoing_bitrate(caps.max_outgoing_bitrate.unwrap_or(0)).await?;
+    Ok(())
+}
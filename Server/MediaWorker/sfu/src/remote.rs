@@ -0,0 +1,71 @@
+//! Remote mode: a media worker started on its own, on another machine, dials the
+//! controller and registers itself instead of being spawned with a one-time code.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::controller_link::{self, ControllerTls, ControllerUrl};
+use crate::{WorkerConfig, WorkerDied};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The first message a remote media worker sends.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Registration {
+    /// Shared with the controller ahead of time.
+    pub token: String,
+
+    /// Lets the controller keep channels close to their members.
+    pub region: String,
+
+    /// How many peers the worker is willing to take.
+    pub capacity: usize,
+
+    #[serde(rename = "announceIPs")]
+    pub announce_ips: Vec<std::net::IpAddr>,
+}
+
+impl Registration {
+    /// Reads `SFU_REMOTE_TOKEN`, `SFU_REMOTE_REGION` and `SFU_REMOTE_CAPACITY`, or returns
+    /// None when the worker is not meant to run in remote mode.
+    pub fn from_env(config: &WorkerConfig) -> Result<Option<Registration>> {
+        let token = match std::env::var("SFU_REMOTE_TOKEN") {
+            Ok(token) if !token.is_empty() => token,
+            _ => return Ok(None),
+        };
+        let capacity = match std::env::var("SFU_REMOTE_CAPACITY") {
+            Ok(capacity) => capacity.parse().context("invalid SFU_REMOTE_CAPACITY")?,
+            Err(_) => 500,
+        };
+        if capacity == 0 {
+            bail!("SFU_REMOTE_CAPACITY must be at least 1");
+        }
+        Ok(Some(Registration {
+            token,
+            region: std::env::var("SFU_REMOTE_REGION").unwrap_or_default(),
+            capacity,
+            announce_ips: vec![config.announce_ip],
+        }))
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::json!({ "register": self }).to_string()
+    }
+}
+
+/// Keeps registering with the controller for as long as the mediasoup worker lives.
+/// Every connection starts out with an empty SFU, the controller recreating its channels.
+pub async fn serve(config: WorkerConfig, url: &ControllerUrl, tls: &ControllerTls, registration: &Registration) -> Result<()> {
+    loop {
+        match controller_link::serve(config.clone(), url, tls, Some(registration)).await {
+            Err(e) if e.is::<WorkerDied>() => return Err(e),
+            Err(e) => error!("lost the controller: {e}"),
+            Ok(()) => info!("controller closed the connection"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
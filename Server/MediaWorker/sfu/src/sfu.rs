@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use log::Level;
+use mediasoup::prelude::*;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::snapshot::Snapshot;
+use crate::{process_command, websocket_link, IncomingMessage, ResponseSender, State, ToClient, ToServer, WorkerConfig, CONTROLLER, NO_CHANNEL};
+
+type LifecycleHook = dyn Fn(Level, &str) + Send + Sync;
+
+/// Counts of everything the SFU currently holds.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SfuStats {
+    pub channels: usize,
+    pub peers: usize,
+    pub transports: usize,
+    pub producers: usize,
+    pub consumers: usize,
+}
+
+impl SfuStats {
+    fn of(state: &State) -> SfuStats {
+        let mut stats = SfuStats {
+            channels: state.channels.len(),
+            ..SfuStats::default()
+        };
+        for peer in state.channels.values().flat_map(|c| c.peers.values()) {
+            stats.peers += 1;
+            stats.transports += peer.transports.len();
+            stats.producers += peer.producers.len();
+            stats.consumers += peer.consumers.len();
+        }
+        stats
+    }
+}
+
+// Every WebRTC transport listens on a UDP and a TCP port, both taken from the RTC port range.
+const PORTS_PER_TRANSPORT: usize = 2;
+
+/// How many RTC ports are taken: two per WebRTC transport and one per dial-in peer.
+/// The loopback relays of the `impairment` feature are left out, being for testing only.
+fn rtc_ports_in_use(state: &State) -> usize {
+    state.channels.values()
+        .flat_map(|c| c.peers.values())
+        .map(|peer| peer.transports.len() * PORTS_PER_TRANSPORT + peer.rtp.is_some() as usize)
+        .sum()
+}
+
+/// Queues up an internal message at a fixed interval, for as long as the SFU runs.
+fn every(interval: std::time::Duration, tx: &mpsc::UnboundedSender<IncomingMessage>, message: fn() -> IncomingMessage) {
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if tx.send(message()).is_err() {
+                break
+            }
+        }
+    });
+}
+
+/// What to do once the mediasoup worker dies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorkerDeathPolicy {
+    /// Stop serving the controller, leaving the restart to whatever supervises the process.
+    #[default]
+    Exit,
+
+    /// Start a new worker and give every channel an empty router on it.
+    Recreate,
+}
+
+/// Returned by `SfuHandle::join` when the SFU stopped because its mediasoup worker died.
+#[derive(Debug, Clone)]
+pub struct WorkerDied {
+    pub reason: String,
+}
+
+impl std::fmt::Display for WorkerDied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mediasoup worker died: {}", self.reason)
+    }
+}
+
+impl std::error::Error for WorkerDied {}
+
+fn worker_settings(config: &WorkerConfig) -> WorkerSettings {
+    let mut worker_settings = WorkerSettings::default();
+    worker_settings.log_level = config.log_level;
+    worker_settings.log_tags = config.log_tags.clone();
+    worker_settings.rtc_port_range = config.rtc_port_range.clone();
+    worker_settings
+}
+
+fn watch_worker(worker: &Worker, tx: &mpsc::UnboundedSender<IncomingMessage>) {
+    let tx = tx.clone();
+    worker.on_dead(move |result| {
+        let reason = match result {
+            Ok(()) => "exited".to_string(),
+            Err(e) => e.to_string(),
+        };
+        _ = tx.send(IncomingMessage::WorkerDied { reason });
+    }).detach();
+}
+
+/// Events the SFU otherwise only learns from mediasoup, for tests to fake.
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub enum SimulatedEvent {
+    WorkerDied {reason: String},
+    DominantSpeaker {channel: usize, producer_id: String},
+    PriorityVolumes {channel: usize, producer_ids: Vec<String>},
+}
+
+impl From<SimulatedEvent> for IncomingMessage {
+    fn from(event: SimulatedEvent) -> IncomingMessage {
+        match event {
+            SimulatedEvent::WorkerDied{reason} => IncomingMessage::WorkerDied { reason },
+            SimulatedEvent::DominantSpeaker{channel, producer_id} => IncomingMessage::DominantSpeaker { channel, producer_id },
+            SimulatedEvent::PriorityVolumes{channel, producer_ids} => IncomingMessage::PriorityVolumes { channel, producer_ids },
+        }
+    }
+}
+
+enum Control {
+    Stats(oneshot::Sender<SfuStats>),
+    Simulate(IncomingMessage),
+    Shutdown,
+}
+
+pub struct SfuBuilder {
+    config: WorkerConfig,
+    lifecycle_hook: Option<Arc<LifecycleHook>>,
+}
+
+impl SfuBuilder {
+    /// Receives what the SFU reports about its own lifecycle: starting, the controller link
+    /// ending, the mediasoup worker dying and controller commands that failed. Without a hook
+    /// they go to the log crate. Everything else, such as what happens to single channels,
+    /// transports or peers, always goes to the log crate.
+    pub fn lifecycle_hook<F: Fn(Level, &str) + Send + Sync + 'static>(mut self, hook: F) -> SfuBuilder {
+        self.lifecycle_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Starts the mediasoup worker. Nothing is routed until a controller link is attached.
+    pub async fn build(self) -> Result<Sfu> {
+        let manager = WorkerManager::new();
+        let worker = manager.create_worker(worker_settings(&self.config)).await?;
+
+        let log = self.lifecycle_hook.unwrap_or_else(|| Arc::new(|level: Level, line: &str| log::log!(level, "{}", line)));
+        log(Level::Info, "starting media worker");
+
+        Ok(Sfu {
+            manager,
+            state: State {
+                worker,
+                channels: HashMap::new(),
+                listen_ip: self.config.listen_ip,
+                announce_ip: self.config.announce_ip,
+                ice_servers: self.config.ice_servers.clone(),
+                moved: HashMap::new(),
+            },
+            config: self.config,
+            log,
+            cpu_sample: None,
+        })
+    }
+}
+
+/// A media worker that has not yet been attached to a controller.
+pub struct Sfu {
+    manager: WorkerManager,
+    state: State,
+    config: WorkerConfig,
+    log: Arc<LifecycleHook>,
+
+    // When the last load report was made and the CPU time used up to then, in milliseconds.
+    cpu_sample: Option<(Instant, u64)>,
+}
+
+impl Sfu {
+    pub fn builder(config: WorkerConfig) -> SfuBuilder {
+        SfuBuilder {
+            config,
+            lifecycle_hook: None,
+        }
+    }
+
+    /// Starts serving a controller. Every item read is a JSON encoded controller message
+    /// and every item written is a JSON encoded reply. The SFU stops once `read` ends.
+    pub fn attach<W, R>(self, write: W, read: R) -> SfuHandle
+    where
+        W: Sink<String, Error = anyhow::Error> + Unpin + Send + 'static,
+        R: Stream<Item = String> + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        watch_worker(&self.state.worker, &tx);
+        if let Some(interval) = self.config.load_report_interval {
+            every(interval, &tx, || IncomingMessage::ReportLoad);
+        }
+        if let Some(file) = &self.config.snapshot_file {
+            every(file.interval, &tx, || IncomingMessage::WriteSnapshot);
+        }
+
+        let read_tx = tx.clone();
+        let read_log = self.log.clone();
+        tokio::spawn(async move {
+            let mut read = read;
+            while let Some(msg) = read.next().await {
+                let message = match serde_json::from_str::<IncomingMessage>(&msg) {
+                    Ok(m) => m,
+                    Err(e) => { read_log(Level::Error, &format!("bad message: {} for input {}", e, msg)); continue }
+                };
+                if read_tx.send(message).is_err() {
+                    break
+                }
+            }
+            read_log(Level::Info, "read task closed");
+            _ = read_tx.send(IncomingMessage::ControllerClosed);
+        });
+
+        let task = tokio::spawn(self.run(Box::new(write), tx, rx, control_rx));
+        SfuHandle {
+            control: control_tx,
+            task,
+        }
+    }
+
+    pub fn attach_websocket<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static>(self, ws_stream: tokio_tungstenite::WebSocketStream<S>) -> SfuHandle {
+        let (write, read) = websocket_link(ws_stream);
+        self.attach(write, read)
+    }
+
+    async fn run(mut self,
+                 mut write: Box<ResponseSender>,
+                 tx: mpsc::UnboundedSender<IncomingMessage>,
+                 mut rx: mpsc::UnboundedReceiver<IncomingMessage>,
+                 mut control_rx: mpsc::UnboundedReceiver<Control>) -> Result<()> {
+        // A dropped handle only detaches from the SFU, it does not stop it.
+        let mut handle_alive = true;
+        self.send_previous_snapshot(write.as_mut()).await;
+        loop {
+            tokio::select! {
+                message = rx.recv() => {
+                    // We hold a sender ourselves so the channel never closes.
+                    let Some(message) = message else { break };
+                    if let IncomingMessage::ControllerClosed = message {
+                        (self.log)(Level::Error, "connection to SFU controller ended");
+                        break
+                    }
+                    if let IncomingMessage::ReportLoad = message {
+                        if let Err(e) = self.report_load(write.as_mut()).await {
+                            (self.log)(Level::Warn, &format!("could not report load: {e}"));
+                        }
+                        continue
+                    }
+                    if let IncomingMessage::WriteSnapshot = message {
+                        if let Some(file) = &self.config.snapshot_file {
+                            if let Err(e) = Snapshot::of(&self.state).write(&file.path).await {
+                                (self.log)(Level::Warn, &format!("{e:#}"));
+                            }
+                        }
+                        continue
+                    }
+                    if let IncomingMessage::WorkerDied{reason} = message {
+                        self.worker_died(reason, &tx, write.as_mut()).await?;
+                        continue
+                    }
+                    if let Err(e) = process_command(&mut self.state, message, &tx, write.as_mut()).await {
+                        (self.log)(Level::Error, &e.to_string());
+                    }
+                }
+                control = control_rx.recv(), if handle_alive => match control {
+                    Some(Control::Stats(reply)) => { _ = reply.send(SfuStats::of(&self.state)); }
+                    Some(Control::Simulate(message)) => { _ = tx.send(message); }
+                    Some(Control::Shutdown) => break,
+                    None => handle_alive = false,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets the controller compare what the last worker held with what it thinks it held.
+    async fn send_previous_snapshot(&self, write: &mut ResponseSender) {
+        let Some(file) = &self.config.snapshot_file else {
+            return
+        };
+        match Snapshot::read(&file.path) {
+            Ok(Some(snapshot)) => {
+                let message = ToServer(NO_CHANNEL, CONTROLLER, ToClient::PreviousSnapshot(snapshot));
+                _ = write.send(serde_json::to_string(&message).unwrap()).await;
+            }
+            Ok(None) => {}
+            Err(e) => (self.log)(Level::Warn, &format!("{e:#}")),
+        }
+    }
+
+    async fn report_load(&mut self, write: &mut ResponseSender) -> Result<()> {
+        let usage = self.state.worker.get_resource_usage().await?;
+        let cpu_time = usage.ru_utime + usage.ru_stime;
+        let now = Instant::now();
+        let cpu_percent = match self.cpu_sample {
+            Some((then, cpu_then)) if now > then => {
+                cpu_time.saturating_sub(cpu_then) as f64 / now.duration_since(then).as_millis() as f64 * 100.0
+            }
+            _ => 0.0,
+        };
+        self.cpu_sample = Some((now, cpu_time));
+
+        let stats = SfuStats::of(&self.state);
+        let ports = self.config.rtc_port_range.clone().count();
+        let message = ToServer(NO_CHANNEL, CONTROLLER, ToClient::LoadReport {
+            channels: stats.channels,
+            peers: stats.peers,
+            producers: stats.producers,
+            consumers: stats.consumers,
+            cpu_percent,
+            free_rtc_ports: ports.saturating_sub(rtc_ports_in_use(&self.state)),
+        });
+        write.send(serde_json::to_string(&message).unwrap()).await
+    }
+
+    /// Tells the controller which channels went down with the worker, then either
+    /// gives up or brings the channels back up empty on a new worker.
+    async fn worker_died(&mut self, reason: String, tx: &mpsc::UnboundedSender<IncomingMessage>, write: &mut ResponseSender) -> Result<()> {
+        (self.log)(Level::Error, &format!("mediasoup worker died: {reason}"));
+        // Breakout rooms were never placed by the controller, it only knows their parents.
+        let mut channels: Vec<usize> = self.state.channels.values()
+            .filter(|channel| channel.parent.is_none())
+            .map(|channel| channel.channel_id)
+            .collect();
+        channels.sort_unstable();
+
+        let recreated = match self.config.worker_death {
+            WorkerDeathPolicy::Exit => false,
+            WorkerDeathPolicy::Recreate => match self.recreate_worker(tx).await {
+                Ok(()) => true,
+                Err(e) => {
+                    (self.log)(Level::Error, &format!("could not recreate mediasoup worker: {e}"));
+                    false
+                }
+            },
+        };
+
+        let message = ToServer(NO_CHANNEL, CONTROLLER, ToClient::WorkerDied {
+            reason: reason.clone(),
+            channels,
+            recreated,
+        });
+        // Whether or not the controller hears about it, a dead worker is not worth serving with.
+        _ = write.send(serde_json::to_string(&message).unwrap()).await;
+        if !recreated {
+            return Err(WorkerDied { reason }.into())
+        }
+        Ok(())
+    }
+
+    async fn recreate_worker(&mut self, tx: &mpsc::UnboundedSender<IncomingMessage>) -> Result<()> {
+        let worker = self.manager.create_worker(worker_settings(&self.config)).await?;
+        watch_worker(&worker, tx);
+        // Their peers are gone, so the rooms would only linger empty. The parents come back
+        // without a breakout.
+        self.state.channels.retain(|_, channel| channel.parent.is_none());
+        for channel in self.state.channels.values_mut() {
+            let router = worker.create_router(RouterOptions::new(channel.codecs.clone())).await?;
+            channel.reset(router, tx).await?;
+        }
+        self.state.worker = worker;
+        self.state.moved.clear();
+        Ok(())
+    }
+}
+
+/// Controls an SFU that is serving a controller.
+pub struct SfuHandle {
+    control: mpsc::UnboundedSender<Control>,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl SfuHandle {
+    pub async fn stats(&self) -> Result<SfuStats> {
+        let (reply, stats) = oneshot::channel();
+        self.control.send(Control::Stats(reply)).map_err(|_| anyhow!("SFU is not running"))?;
+        Ok(stats.await?)
+    }
+
+    /// Feeds the SFU an event as if mediasoup had raised it. It is handled after whatever
+    /// the SFU has already read from the controller. For tests only.
+    #[doc(hidden)]
+    pub fn simulate(&self, event: SimulatedEvent) -> Result<()> {
+        self.control.send(Control::Simulate(event.into())).map_err(|_| anyhow!("SFU is not running"))
+    }
+
+    /// Closes every channel and the mediasoup worker.
+    pub async fn shutdown(self) -> Result<()> {
+        _ = self.control.send(Control::Shutdown);
+        self.join().await
+    }
+
+    /// Waits for the controller to close the link. Fails with `WorkerDied` if the SFU
+    /// stopped because of its mediasoup worker instead.
+    pub async fn join(self) -> Result<()> {
+        self.task.await?
+    }
+}
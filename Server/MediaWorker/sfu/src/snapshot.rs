@@ -0,0 +1,170 @@
+//! A serializable picture of what the SFU holds, without any of the mediasoup objects.
+//! The controller asks for it to check its own view of the channels against ours, and
+//! a copy kept on disk tells a restarted worker what the previous one was holding.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use mediasoup::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::profile::BitrateCaps;
+use crate::{Channel, Peer, PeerID, PeerRole, State};
+
+/// Where and how often to write snapshots, as given by `SFU_SNAPSHOT_PATH` and `SFU_SNAPSHOT_SECONDS`.
+#[derive(Debug, Clone)]
+pub struct SnapshotFile {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl SnapshotFile {
+    pub fn from_env() -> Option<SnapshotFile> {
+        let path = std::env::var("SFU_SNAPSHOT_PATH").ok().filter(|path| !path.is_empty())?;
+        let interval = match std::env::var("SFU_SNAPSHOT_SECONDS") {
+            Ok(seconds) => seconds.parse().expect("invalid SFU_SNAPSHOT_SECONDS"),
+            Err(_) => 30,
+        };
+        Some(SnapshotFile {
+            path: PathBuf::from(path),
+            interval: Duration::from_secs(interval.max(1)),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ProducerSnapshot {
+    id: String,
+    kind: MediaKind,
+    paused: bool,
+
+    // Set for whispers.
+    audience: Option<Vec<PeerID>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConsumerSnapshot {
+    id: String,
+    // The one asked for, not the relay it may be reading through.
+    #[serde(rename = "producerID")]
+    producer_id: String,
+    // Paused by deafening or last-N.
+    paused: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PeerSnapshot {
+    #[serde(rename = "peerID")]
+    peer_id: PeerID,
+    deaf: bool,
+    role: PeerRole,
+    hand_raised: bool,
+    #[serde(default)]
+    priority: bool,
+
+    // Dial-in peers have no voice state in the controller.
+    rtp: bool,
+    producers: Vec<ProducerSnapshot>,
+    #[serde(default)]
+    consumers: Vec<ConsumerSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChannelSnapshot {
+    channel: usize,
+    // Set for breakout rooms.
+    #[serde(default)]
+    parent: Option<usize>,
+    codecs: Vec<RtpCodecCapability>,
+    #[serde(default)]
+    bitrate: BitrateCaps,
+    last_n: Option<usize>,
+    peers: Vec<PeerSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Snapshot {
+    // Milliseconds since the Unix epoch.
+    taken_at: u64,
+    channels: Vec<ChannelSnapshot>,
+}
+
+fn peer_snapshot(channel: &Channel, peer_id: PeerID, peer: &Peer) -> PeerSnapshot {
+    let mut producers: Vec<ProducerSnapshot> = peer.producers.values().map(|producer| ProducerSnapshot {
+        id: producer.id().to_string(),
+        kind: producer.kind(),
+        paused: producer.paused(),
+        audience: peer.audiences.get(&producer.id().to_string()).cloned(),
+    }).collect();
+    producers.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut consumers: Vec<ConsumerSnapshot> = peer.consumers.values().map(|consumer| ConsumerSnapshot {
+        id: consumer.id().to_string(),
+        producer_id: channel.original_producer(&consumer.producer_id().to_string()),
+        paused: consumer.paused(),
+    }).collect();
+    consumers.sort_by(|a, b| a.id.cmp(&b.id));
+    PeerSnapshot {
+        peer_id,
+        deaf: peer.deaf,
+        role: peer.role,
+        hand_raised: peer.hand_raised,
+        priority: peer.priority,
+        rtp: peer.rtp.is_some(),
+        producers,
+        consumers,
+    }
+}
+
+fn channel_snapshot(channel: &Channel) -> ChannelSnapshot {
+    let mut peers: Vec<PeerSnapshot> = channel.peers.iter()
+        .map(|(peer_id, peer)| peer_snapshot(channel, *peer_id, peer))
+        .collect();
+    peers.sort_by_key(|peer| peer.peer_id);
+    ChannelSnapshot {
+        channel: channel.channel_id,
+        parent: channel.parent,
+        codecs: channel.codecs.clone(),
+        bitrate: channel.bitrate,
+        last_n: channel.last_n(),
+        peers,
+    }
+}
+
+impl Snapshot {
+    pub(crate) fn of(state: &State) -> Snapshot {
+        let mut channels: Vec<ChannelSnapshot> = state.channels.values().map(channel_snapshot).collect();
+        channels.sort_by_key(|channel| channel.channel);
+        Snapshot {
+            taken_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64),
+            channels,
+        }
+    }
+
+    /// Reads the snapshot a previous worker left behind, if there is one.
+    pub(crate) fn read(path: &Path) -> Result<Option<Snapshot>> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("could not read snapshot {}", path.display())),
+        };
+        let snapshot = serde_json::from_slice(&json).with_context(|| format!("invalid snapshot {}", path.display()))?;
+        Ok(Some(snapshot))
+    }
+
+    /// Replaces the snapshot on disk, never leaving a half written one behind.
+    pub(crate) async fn write(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        tokio::fs::write(&partial, serde_json::to_vec(self)?).await
+            .with_context(|| format!("could not write snapshot {}", path.display()))?;
+        tokio::fs::rename(&partial, path).await
+            .with_context(|| format!("could not write snapshot {}", path.display()))?;
+        Ok(())
+    }
+}
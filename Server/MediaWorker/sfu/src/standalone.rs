@@ -0,0 +1,259 @@
+//! Lets browsers connect to the SFU directly instead of having the Node server relay
+//! their messages. Browsers authenticate with a token, signed by whoever shares the
+//! secret with us, that assigns them a channel and a peer ID.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use mediasoup::prelude::*;
+use serde_json::json;
+use sha2::Sha256;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{PeerID, Sfu, SfuHandle};
+
+type Clients = Arc<Mutex<HashMap<(usize, PeerID), UnboundedSender<String>>>>;
+
+const DEFAULT_CODECS: &str = r#"[{"kind": "audio", "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []}]"#;
+
+#[derive(Debug, Clone)]
+pub struct StandaloneConfig {
+    /// Shared with the service that hands out tokens.
+    pub secret: Vec<u8>,
+
+    /// Used for every channel created by a browser joining it.
+    pub codecs: Vec<RtpCodecCapability>,
+}
+
+impl StandaloneConfig {
+    pub fn from_env() -> StandaloneConfig {
+        let secret = std::env::var("SFU_STANDALONE_SECRET").expect("SFU_STANDALONE_SECRET missing from env");
+        if secret.is_empty() {
+            panic!("SFU_STANDALONE_SECRET must not be empty");
+        }
+        let codecs = std::env::var("SFU_STANDALONE_CODECS").unwrap_or_else(|_| DEFAULT_CODECS.to_string());
+        StandaloneConfig {
+            secret: secret.into_bytes(),
+            codecs: serde_json::from_str(&codecs).expect("SFU_STANDALONE_CODECS is not a valid list of codecs"),
+        }
+    }
+}
+
+fn signature(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Creates a token of the form `channel.peer.expires.signature` where `expires` is in
+/// seconds since the Unix epoch and `signature` is a hex encoded HMAC-SHA256 of the rest.
+pub fn sign_token(secret: &[u8], channel: usize, peer: PeerID, expires: u64) -> String {
+    let payload = format!("{channel}.{peer}.{expires}");
+    let signature = signature(secret, &payload).finalize().into_bytes();
+    let hex: String = signature.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{payload}.{hex}")
+}
+
+fn verify_token(secret: &[u8], token: &str, now: u64) -> Result<(usize, PeerID)> {
+    let Some((payload, hex)) = token.rsplit_once('.') else {
+        bail!("token is missing a signature");
+    };
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        bail!("malformed token signature");
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    if signature(secret, payload).verify_slice(&bytes).is_err() {
+        bail!("bad token signature");
+    }
+
+    let mut parts = payload.split('.');
+    let (Some(channel), Some(peer), Some(expires), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        bail!("malformed token");
+    };
+    if expires.parse::<u64>()? < now {
+        bail!("token has expired");
+    }
+    Ok((channel.parse()?, peer.parse()?))
+}
+
+fn token_from_query(request: &Request) -> Option<&str> {
+    request.uri().query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Hands a reply from the SFU to the browser it is addressed to.
+fn route_reply(clients: &Clients, reply: &str) {
+    // Heartbeats and such are not meant for browsers.
+    let Ok((channel, peer, message)) = serde_json::from_str::<(usize, PeerID, serde_json::Value)>(reply) else {
+        return
+    };
+    if let Some(client) = clients.lock().unwrap().get(&(channel, peer)) {
+        _ = client.send(message.to_string());
+    }
+}
+
+struct Shared {
+    config: StandaloneConfig,
+    clients: Clients,
+
+    // How many browsers are in each channel, so that channels go once the last one leaves.
+    channels: Mutex<HashMap<usize, usize>>,
+    to_sfu: UnboundedSender<String>,
+}
+
+async fn handle_browser(socket: TcpStream, shared: Arc<Shared>) -> Result<()> {
+    let mut assigned = None;
+    let ws_stream = tokio_tungstenite::accept_hdr_async(socket, |request: &Request, response: Response| {
+        let verified = token_from_query(request)
+            .ok_or_else(|| anyhow!("token missing"))
+            .and_then(|token| verify_token(&shared.config.secret, token, unix_now()));
+        match verified {
+            Ok(ids) => {
+                assigned = Some(ids);
+                Ok(response)
+            }
+            Err(e) => {
+                let mut error = ErrorResponse::new(Some(e.to_string()));
+                *error.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(error)
+            }
+        }
+    }).await?;
+    let Some((channel, peer)) = assigned else {
+        unreachable!("handshake succeeded without a verified token");
+    };
+
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel();
+    {
+        let mut clients = shared.clients.lock().unwrap();
+        if clients.contains_key(&(channel, peer)) {
+            bail!("peer {peer} is already connected to channel {channel}");
+        }
+        clients.insert((channel, peer), client_tx);
+    }
+    {
+        // Sent while holding the lock, so that a channel is never created before the last browser removed it.
+        let mut channels = shared.channels.lock().unwrap();
+        let browsers = channels.entry(channel).or_default();
+        if *browsers == 0 {
+            _ = shared.to_sfu.send(json!({"type": "NewChannel", "channel": channel, "codecs": shared.config.codecs}).to_string());
+        }
+        *browsers += 1;
+        _ = shared.to_sfu.send(json!({"type": "AddPeer", "channel": channel, "peer": peer}).to_string());
+    }
+    info!("browser joined channel {channel} as peer {peer}");
+
+    let (mut write, mut read) = ws_stream.split();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = client_rx.recv().await {
+            if write.send(Message::text(message)).await.is_err() {
+                break
+            }
+        }
+    });
+
+    while let Some(message) = read.next().await {
+        let Message::Text(text) = (match message {
+            Ok(message) => message,
+            Err(e) => { error!("failed to read from browser: {e}"); break }
+        }) else {
+            continue
+        };
+        // The message itself is validated by the SFU, we only make sure it can not
+        // pretend to come from some other channel or peer.
+        let message: serde_json::Value = match serde_json::from_str(&text) {
+            Ok(m) => m,
+            Err(e) => { error!("bad message from browser: {e}"); continue }
+        };
+        _ = shared.to_sfu.send(json!({"type": "HandleClient", "channel": channel, "peer": peer, "message": message}).to_string());
+    }
+
+    shared.clients.lock().unwrap().remove(&(channel, peer));
+    {
+        let mut channels = shared.channels.lock().unwrap();
+        _ = shared.to_sfu.send(json!({"type": "RemovePeer", "channel": channel, "peer": peer}).to_string());
+        let browsers = channels.entry(channel).or_default();
+        *browsers = browsers.saturating_sub(1);
+        if *browsers == 0 {
+            channels.remove(&channel);
+            _ = shared.to_sfu.send(json!({"type": "RemoveChannel", "channel": channel}).to_string());
+        }
+    }
+    writer.abort();
+    info!("browser left channel {channel} as peer {peer}");
+    Ok(())
+}
+
+/// An SFU serving browsers directly, with no controller in between.
+pub struct Standalone {
+    shared: Arc<Shared>,
+    handle: SfuHandle,
+}
+
+impl Standalone {
+    /// Attaches the SFU to the browsers that connect once `serve` runs.
+    pub fn attach(sfu: Sfu, config: StandaloneConfig) -> Standalone {
+        let clients = Clients::default();
+        let (to_sfu, from_browsers) = mpsc::unbounded_channel::<String>();
+
+        let write = Box::pin(futures_util::sink::unfold(clients.clone(), |clients, reply: String| async move {
+            route_reply(&clients, &reply);
+            Ok::<_, anyhow::Error>(clients)
+        }));
+        let read = Box::pin(futures_util::stream::unfold(from_browsers, |mut rx| async move {
+            rx.recv().await.map(|message| (message, rx))
+        }));
+        let handle = sfu.attach(write, read);
+
+        Standalone {
+            shared: Arc::new(Shared {
+                config,
+                clients,
+                channels: Mutex::new(HashMap::new()),
+                to_sfu,
+            }),
+            handle,
+        }
+    }
+
+    pub fn handle(&self) -> &SfuHandle {
+        &self.handle
+    }
+
+    /// Serves browsers connecting to `listener` until it fails.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (socket, address) = listener.accept().await?;
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_browser(socket, shared).await {
+                    error!("browser at {address}: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// Serves browsers connecting to `listener` until it fails.
+pub async fn serve(sfu: Sfu, listener: TcpListener, config: StandaloneConfig) -> Result<()> {
+    Standalone::attach(sfu, config).serve(listener).await
+}
@@ -0,0 +1,197 @@
+//! Forwards what mediasoup sees of a producer's RTP stream to the controller, for
+//! debugging things like robotic audio. Traces are rate limited and always expire.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use mediasoup::data_structures::TraceEventDirection;
+use mediasoup::prelude::*;
+use mediasoup::producer::{ProducerTraceEventData, ProducerTraceEventType};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{IncomingMessage, PeerID, ToClient, CONTROLLER};
+
+const MAX_DURATION: Duration = Duration::from_secs(120);
+const MAX_EVENTS_PER_SECOND: u32 = 200;
+
+pub(crate) struct Trace {
+    pub(crate) deadline: Instant,
+
+    // Unregisters the handler once the trace is dropped, so that traces replacing
+    // each other do not stack up handlers on the producer.
+    _handler: HandlerId,
+}
+
+pub(crate) fn parse_trace_event(s: &str) -> Result<ProducerTraceEventType> {
+    Ok(match s {
+        "rtp" =>      ProducerTraceEventType::Rtp,
+        "keyframe" => ProducerTraceEventType::KeyFrame,
+        "nack" =>     ProducerTraceEventType::Nack,
+        "pli" =>      ProducerTraceEventType::Pli,
+        "fir" =>      ProducerTraceEventType::Fir,
+        _ => bail!("unsupported trace event: {s}"),
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum TraceDirection {
+    In,
+    Out,
+}
+
+/// What the controller is told about a trace event. Our own type rather than the one of
+/// mediasoup, so that it reads the same whichever mediasoup version we are built with.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TraceEvent {
+    // One of the names parse_trace_event() accepts.
+    #[serde(rename = "type")]
+    kind: String,
+    timestamp: u64,
+    direction: TraceDirection,
+
+    // The stream a key frame was asked for, for PLI and FIR.
+    ssrc: Option<u32>,
+}
+
+impl From<&ProducerTraceEventData> for TraceEvent {
+    fn from(data: &ProducerTraceEventData) -> TraceEvent {
+        let (kind, timestamp, direction, ssrc) = match data {
+            ProducerTraceEventData::Rtp { timestamp, direction, .. } => ("rtp", *timestamp, *direction, None),
+            ProducerTraceEventData::KeyFrame { timestamp, direction, .. } => ("keyframe", *timestamp, *direction, None),
+            ProducerTraceEventData::Nack { timestamp, direction, .. } => ("nack", *timestamp, *direction, None),
+            ProducerTraceEventData::Pli { timestamp, direction, info } => ("pli", *timestamp, *direction, Some(info.ssrc)),
+            ProducerTraceEventData::Fir { timestamp, direction, info } => ("fir", *timestamp, *direction, Some(info.ssrc)),
+        };
+        TraceEvent {
+            kind: kind.to_string(),
+            timestamp,
+            direction: match direction {
+                TraceEventDirection::In => TraceDirection::In,
+                TraceEventDirection::Out => TraceDirection::Out,
+            },
+            ssrc,
+        }
+    }
+}
+
+/// How many events a trace may still forward.
+struct Budget {
+    deadline: Instant,
+    max_per_second: u32,
+    second: Instant,
+    used: u32,
+}
+
+impl Budget {
+    fn new(now: Instant, duration: Duration, max_per_second: u32) -> Budget {
+        Budget {
+            deadline: now + duration,
+            max_per_second,
+            second: now,
+            used: 0,
+        }
+    }
+
+    /// Takes one event out of the budget, if there is any left.
+    fn take(&mut self, now: Instant) -> bool {
+        if now > self.deadline {
+            return false
+        }
+        if now.duration_since(self.second) >= Duration::from_secs(1) {
+            self.second = now;
+            self.used = 0;
+        }
+        if self.used >= self.max_per_second {
+            return false
+        }
+        self.used += 1;
+        true
+    }
+}
+
+/// Starts forwarding trace events of the producer as `ToClient::ProducerTrace` messages,
+/// addressed to the controller. Events stop flowing once the returned trace is dropped.
+pub(crate) async fn start_trace(producer: &Producer,
+                                channel: usize,
+                                peer: PeerID,
+                                events: &[String],
+                                seconds: u64,
+                                max_per_second: u32,
+                                tx: &UnboundedSender<IncomingMessage>) -> Result<Trace> {
+    let events = events.iter().map(|e| parse_trace_event(e)).collect::<Result<Vec<_>>>()?;
+    let duration = Duration::from_secs(seconds).min(MAX_DURATION);
+    let budget = Budget::new(Instant::now(), duration, max_per_second.min(MAX_EVENTS_PER_SECOND));
+    let deadline = budget.deadline;
+
+    let producer_id = producer.id().to_string();
+    let budget = Mutex::new(budget);
+    let tx_2 = tx.clone();
+    let producer_id_2 = producer_id.clone();
+    let handler = producer.on_trace(move |data: &ProducerTraceEventData| {
+        if !budget.lock().unwrap().take(Instant::now()) {
+            return
+        }
+        _ = tx_2.send(IncomingMessage::MessageTo {
+            channel,
+            peer: CONTROLLER,
+            message: ToClient::ProducerTrace {
+                peer_id: peer,
+                producer_id: producer_id_2.clone(),
+                event: data.into(),
+            },
+        });
+    });
+    producer.enable_trace_event(events).await?;
+
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        _ = tx.send(IncomingMessage::TraceExpired { channel, peer, producer_id });
+    });
+
+    Ok(Trace {
+        deadline,
+        _handler: handler,
+    })
+}
+
+pub(crate) async fn stop_trace(producer: &Producer) -> Result<()> {
+    producer.enable_trace_event(vec![]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_refills_every_second() {
+        let start = Instant::now();
+        let mut budget = Budget::new(start, Duration::from_secs(10), 3);
+        assert_eq!((0..5).map(|_| budget.take(start)).collect::<Vec<_>>(), [true, true, true, false, false]);
+        assert!(!budget.take(start + Duration::from_millis(999)));
+        assert!(budget.take(start + Duration::from_secs(1)));
+        assert!(budget.take(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn budget_runs_out_at_the_deadline() {
+        let start = Instant::now();
+        let mut budget = Budget::new(start, Duration::from_secs(2), 100);
+        assert!(budget.take(start + Duration::from_secs(2)));
+        assert!(!budget.take(start + Duration::from_millis(2001)));
+        assert!(!budget.take(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn trace_events_are_parsed_by_name() {
+        for name in ["rtp", "keyframe", "nack", "pli", "fir"] {
+            assert!(parse_trace_event(name).is_ok(), "{name}");
+        }
+        assert!(parse_trace_event("probation").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+//! STUN/TURN servers handed to clients along with every transport, so that clients
+//! behind symmetric NATs have a relay to fall back on.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::PeerID;
+
+const DEFAULT_TTL: u64 = 24 * 60 * 60;
+
+fn default_ttl() -> u64 {
+    DEFAULT_TTL
+}
+
+/// A server as configured through `SFU_ICE_SERVERS`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    pub urls: Vec<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub credential: Option<String>,
+
+    /// Shared with the TURN server (`static-auth-secret` in coturn). When present, a
+    /// short-lived username and credential are generated for every transport instead.
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// How many seconds generated credentials stay valid.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+}
+
+/// A server as understood by `RTCPeerConnection`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ClientIceServer {
+    urls: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+}
+
+/// The TURN REST API scheme: the username is `expires:user` and the credential is
+/// the base64 encoded HMAC-SHA1 of the username, keyed with the shared secret.
+fn rest_api_credentials(secret: &str, user: &str, expires: u64) -> (String, String) {
+    let username = format!("{expires}:{user}");
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    let credential = base64::encode(mac.finalize().into_bytes());
+    (username, credential)
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl IceServer {
+    pub(crate) fn for_peer(&self, channel: usize, peer: PeerID) -> ClientIceServer {
+        let (username, credential) = match &self.secret {
+            Some(secret) => {
+                let (username, credential) = rest_api_credentials(secret, &format!("{channel}-{peer}"), unix_now() + self.ttl);
+                (Some(username), Some(credential))
+            }
+            None => (self.username.clone(), self.credential.clone()),
+        };
+        ClientIceServer {
+            urls: self.urls.clone(),
+            username,
+            credential,
+        }
+    }
+}
+
+pub(crate) fn ice_servers_from_env() -> Vec<IceServer> {
+    match std::env::var("SFU_ICE_SERVERS") {
+        Ok(servers) if !servers.is_empty() => serde_json::from_str(&servers).expect("SFU_ICE_SERVERS is not a valid list of ICE servers"),
+        _ => vec![],
+    }
+}
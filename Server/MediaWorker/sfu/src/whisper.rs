@@ -0,0 +1,110 @@
+//! Whispering: producers that only some peers of a channel get to hear, such as a team
+//! lead talking to part of the team. The others are never told about the producer, can
+//! not consume it and do not get it in their mix or in the broadcast.
+
+use anyhow::{bail, Result};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{Channel, IncomingMessage, NewProducer, PeerID, ToClient};
+
+impl Channel {
+    /// The peers a producer is restricted to, if it is.
+    pub(crate) fn audience_of(&self, producer_id: &str) -> Option<&[PeerID]> {
+        self.peers.values().find_map(|peer| peer.audiences.get(producer_id)).map(Vec::as_slice)
+    }
+
+    /// Whether a peer may hear a producer. Owners can always hear their own.
+    pub(crate) fn may_consume(&self, peer_id: PeerID, producer_id: &str) -> bool {
+        match self.audience_of(producer_id) {
+            None => true,
+            Some(audience) => audience.contains(&peer_id) || self.owner_of(producer_id) == Some(peer_id),
+        }
+    }
+
+    /// Tells everyone who may hear them about new producers of a peer.
+    pub(crate) fn announce_producers(&self, owner: PeerID, producer_ids: &[String], tx: &UnboundedSender<IncomingMessage>) {
+        if producer_ids.is_empty() {
+            return
+        }
+        if producer_ids.iter().all(|producer_id| self.audience_of(producer_id).is_none()) {
+            _ = tx.send(IncomingMessage::BroadCast {
+                channel: self.channel_id,
+                from_peer: owner,
+                message: ToClient::NewProducers(producer_ids.iter().map(|producer_id| self.new_producer(owner, producer_id)).collect()),
+            });
+            return
+        }
+        for (peer_id, peer) in &self.peers {
+            if *peer_id == owner || peer.rtp.is_some() {
+                continue
+            }
+            let audible: Vec<NewProducer> = producer_ids.iter()
+                .filter(|producer_id| self.may_consume(*peer_id, producer_id))
+                .map(|producer_id| self.new_producer(owner, producer_id))
+                .collect();
+            if !audible.is_empty() {
+                _ = tx.send(IncomingMessage::MessageTo {
+                    channel: self.channel_id,
+                    peer: *peer_id,
+                    message: ToClient::NewProducers(audible),
+                });
+            }
+        }
+    }
+
+    /// Restricts a producer to some peers, or lets everyone hear it again. Peers that lose
+    /// it have their consumers closed, peers that gain it are told about it.
+    pub(crate) async fn set_audience(&mut self,
+                                     owner: PeerID,
+                                     producer_id: &str,
+                                     audience: Option<Vec<PeerID>>,
+                                     tx: &UnboundedSender<IncomingMessage>) -> Result<()> {
+        let Some(peer) = self.peers.get(&owner) else {
+            bail!("peer ID not found in channel");
+        };
+        let Some(producer) = peer.producers.get(producer_id).cloned() else {
+            bail!("producer ID not found in peer");
+        };
+        let others: Vec<PeerID> = self.peers.keys().copied().filter(|peer_id| *peer_id != owner).collect();
+        let before: Vec<bool> = others.iter().map(|peer_id| self.may_consume(*peer_id, producer_id)).collect();
+        let Some(peer) = self.peers.get_mut(&owner) else {
+            unreachable!();
+        };
+        match audience {
+            Some(audience) => peer.audiences.insert(producer_id.to_string(), audience),
+            None => peer.audiences.remove(producer_id),
+        };
+
+        // Easiest to take it out of every mix and put it back where it belongs.
+        self.unmix_producer(producer_id);
+        self.mix_producer(&producer, owner).await?;
+
+        let mut gained = vec![];
+        for (peer_id, could) in others.into_iter().zip(before) {
+            match (could, self.may_consume(peer_id, producer_id)) {
+                (true, false) => {
+                    let lost: Vec<String> = self.peers.get(&peer_id).into_iter()
+                        .flat_map(|peer| peer.consumers.iter())
+                        .filter(|(_, consumer)| consumer.producer_id() == producer.id())
+                        .map(|(consumer_id, _)| consumer_id.clone())
+                        .collect();
+                    for consumer_id in lost {
+                        self.remove_consumer(peer_id, &consumer_id, tx);
+                    }
+                }
+                (false, true) => gained.push(peer_id),
+                _ => {}
+            }
+        }
+        for peer_id in gained {
+            if self.peers.get(&peer_id).is_some_and(|peer| peer.rtp.is_none()) {
+                _ = tx.send(IncomingMessage::MessageTo {
+                    channel: self.channel_id,
+                    peer: peer_id,
+                    message: ToClient::NewProducers(vec![self.new_producer(owner, producer_id)]),
+                });
+            }
+        }
+        Ok(())
+    }
+}
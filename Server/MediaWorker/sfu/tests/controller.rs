@@ -0,0 +1,874 @@
+//! Drives a media worker through an in-process controller, the same way the Node server does.
+
+use futures_util::{SinkExt, StreamExt};
+use media_worker_sfu::{IceServer, Sfu, SfuHandle, SimulatedEvent, WorkerConfig, WorkerDeathPolicy, WorkerLogLevel};
+use serde_json::{json, Value};
+use tokio::io::DuplexStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+const CHANNEL: usize = 7;
+
+struct Controller {
+    ws: WebSocketStream<DuplexStream>,
+    worker: SfuHandle,
+}
+
+impl Controller {
+    async fn start() -> Controller {
+        Controller::start_with(vec![]).await
+    }
+
+    async fn start_with(ice_servers: Vec<IceServer>) -> Controller {
+        Controller::start_config(WorkerConfig {
+            ice_servers,
+            ..config()
+        }).await
+    }
+
+    async fn start_config(config: WorkerConfig) -> Controller {
+        let (controller_io, worker_io) = tokio::io::duplex(1 << 16);
+        let (ws, worker_ws) = tokio::join!(
+            tokio_tungstenite::accept_async(controller_io),
+            tokio_tungstenite::client_async("ws://controller/media-worker/0/test", worker_io),
+        );
+        let (worker_ws, _) = worker_ws.expect("worker could not connect");
+        let worker = Sfu::builder(config).build().await.unwrap().attach_websocket(worker_ws);
+        Controller {
+            ws: ws.expect("controller could not accept"),
+            worker,
+        }
+    }
+
+    async fn send(&mut self, message: Value) {
+        self.ws.send(Message::text(message.to_string())).await.unwrap();
+    }
+
+    async fn handle_client(&mut self, peer: usize, message: Value) {
+        self.send(json!({"type": "HandleClient", "channel": CHANNEL, "peer": peer, "message": message})).await;
+    }
+
+    /// Waits for the next (channel, peer, message) tuple sent by the worker, for any channel.
+    async fn receive_any(&mut self) -> (usize, usize, Value) {
+        loop {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), self.ws.next())
+                .await
+                .expect("timed out waiting for the worker")
+                .expect("worker closed the connection")
+                .unwrap();
+            let Message::Text(text) = message else {
+                continue
+            };
+            if text == "heartbeat" {
+                continue
+            }
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+
+    /// Waits for the next (channel, peer, message) tuple sent by the worker, skipping load reports.
+    async fn receive(&mut self) -> (usize, usize, Value) {
+        loop {
+            let (channel, peer, message) = self.receive_any().await;
+            if message.get("loadReport").is_some() {
+                continue
+            }
+            assert_eq!(channel, CHANNEL);
+            return (channel, peer, message);
+        }
+    }
+
+    async fn receive_for(&mut self, peer: usize, key: &str) -> Value {
+        let (_, to, message) = self.receive().await;
+        assert_eq!(to, peer, "unexpected recipient for {message}");
+        message.get(key).unwrap_or_else(|| panic!("expected {key}, got {message}")).clone()
+    }
+
+    /// Asks for a snapshot of the worker and waits for it.
+    async fn snapshot(&mut self) -> Value {
+        self.send(json!({"type": "Snapshot"})).await;
+        loop {
+            let (channel, peer, message) = self.receive_any().await;
+            if let Some(snapshot) = message.get("snapshot") {
+                // Addressed to the controller rather than to a channel.
+                assert_eq!((channel, peer), (usize::MAX, usize::MAX));
+                return snapshot.clone();
+            }
+        }
+    }
+
+    /// Fakes an event that would otherwise come from mediasoup.
+    fn simulate(&self, event: SimulatedEvent) {
+        self.worker.simulate(event).unwrap();
+    }
+
+    async fn stop(mut self) {
+        self.ws.close(None).await.unwrap();
+        self.worker.join().await.unwrap();
+    }
+}
+
+fn config() -> WorkerConfig {
+    WorkerConfig {
+        log_level: WorkerLogLevel::Warn,
+        log_tags: vec![],
+        rtc_port_range: 40000..=40999,
+        listen_ip: "127.0.0.1".parse().unwrap(),
+        // Any address will do as long as it is not refused by allowed_announce_ip().
+        announce_ip: "192.0.2.1".parse().unwrap(),
+        ice_servers: vec![],
+        worker_death: WorkerDeathPolicy::Exit,
+        load_report_interval: None,
+        snapshot_file: None,
+    }
+}
+
+fn opus_codec() -> Value {
+    json!({"kind": "audio", "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []})
+}
+
+fn dtls_parameters() -> Value {
+    let fingerprint = vec!["AB"; 32].join(":");
+    json!({"role": "client", "fingerprints": [{"algorithm": "sha-256", "value": fingerprint}]})
+}
+
+fn opus_rtp_parameters(ssrc: u32) -> Value {
+    json!({
+        "mid": "0",
+        "codecs": [{"mimeType": "audio/opus", "payloadType": 111, "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []}],
+        "headerExtensions": [],
+        "encodings": [{"ssrc": ssrc}],
+        "rtcp": {"cname": format!("peer-{ssrc}"), "reducedSize": true},
+    })
+}
+
+fn empty_capabilities() -> Value {
+    json!({"codecs": [], "headerExtensions": []})
+}
+
+async fn join(controller: &mut Controller, peer: usize) {
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": peer})).await;
+    controller.receive_for(peer, "capabilities").await;
+}
+
+/// Creates and connects a transport for the peer, returning its ID.
+async fn connected_transport(controller: &mut Controller, peer: usize, errand: usize) -> String {
+    controller.handle_client(peer, json!({"createTransport": {"rtpCapabilities": empty_capabilities(), "forceTCP": false, "errand": errand}})).await;
+    let created = controller.receive_for(peer, "transportCreated").await;
+    assert_eq!(created["errand"], errand);
+    let transport_id = created["data"]["id"].as_str().unwrap().to_string();
+
+    controller.handle_client(peer, json!({"connectTransport": {"dtlsParameters": dtls_parameters(), "transportID": transport_id, "errand": errand + 1}})).await;
+    let connected = controller.receive_for(peer, "transportConnected").await;
+    assert_eq!(connected["errand"], errand + 1);
+    transport_id
+}
+
+async fn produce(controller: &mut Controller, peer: usize, transport_id: &str, errand: usize) -> String {
+    controller.handle_client(peer, json!({"produceTransport": {"producerTransportID": transport_id, "kind": "audio", "rtpParameters": opus_rtp_parameters(peer as u32 + 1000), "errand": errand}})).await;
+    let producing = controller.receive_for(peer, "transportProducing").await;
+    assert_eq!(producing["errand"], errand);
+    producing["producerID"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn peer_receives_capabilities() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 1})).await;
+    let capabilities = controller.receive_for(1, "capabilities").await;
+    assert_eq!(capabilities["codecs"][0]["mimeType"], "audio/opus");
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn producers_are_announced_and_cleaned_up() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let producer_id = produce(&mut controller, 1, &transport_id, 20).await;
+
+    let announced = controller.receive_for(2, "newProducers").await;
+    assert_eq!(announced, json!([{"peerID": 1, "producerID": producer_id}]));
+
+    controller.handle_client(2, json!({"getProducers": {}})).await;
+    let listed = controller.receive_for(2, "newProducers").await;
+    assert_eq!(listed, json!([{"peerID": 1, "producerID": producer_id}]));
+
+    controller.send(json!({"type": "RemovePeer", "channel": CHANNEL, "peer": 1})).await;
+    let closed = controller.receive_for(2, "producerClosed").await;
+    assert_eq!(closed, json!({"peerID": 1, "producerID": producer_id}));
+    assert_eq!(controller.receive_for(2, "peerLeft").await, json!(1));
+
+    controller.handle_client(2, json!({"getProducers": {}})).await;
+    assert_eq!(controller.receive_for(2, "newProducers").await, json!([]));
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn closing_a_producer_notifies_the_channel() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let producer_id = produce(&mut controller, 1, &transport_id, 20).await;
+    controller.receive_for(2, "newProducers").await;
+
+    controller.handle_client(1, json!({"producerClosed": {"producerID": producer_id}})).await;
+    let closed = controller.receive_for(2, "producerClosed").await;
+    assert_eq!(closed, json!({"peerID": 1, "producerID": producer_id}));
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn whispers_only_reach_their_audience() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    let mut receiving = vec![];
+    for peer in [2, 3] {
+        controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": peer})).await;
+        let capabilities = controller.receive_for(peer, "capabilities").await;
+        receiving.push((capabilities, connected_transport(&mut controller, peer, 1).await));
+    }
+
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    controller.handle_client(1, json!({"produceTransport": {"producerTransportID": transport_id, "kind": "audio", "rtpParameters": opus_rtp_parameters(1001), "audience": [2], "errand": 20}})).await;
+    let producer_id = controller.receive_for(1, "transportProducing").await["producerID"].as_str().unwrap().to_string();
+    let announced = controller.receive_for(2, "newProducers").await;
+    assert_eq!(announced, json!([{"peerID": 1, "producerID": producer_id}]));
+
+    controller.handle_client(3, json!({"getProducers": {}})).await;
+    assert_eq!(controller.receive_for(3, "newProducers").await, json!([]));
+
+    // Failed commands get no reply, so the getProducers answer comes first.
+    let (capabilities, transport_id) = &receiving[1];
+    controller.handle_client(3, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": transport_id, "producerID": producer_id}})).await;
+    controller.handle_client(3, json!({"getProducers": {}})).await;
+    assert_eq!(controller.receive_for(3, "newProducers").await, json!([]));
+
+    let (capabilities, transport_id) = &receiving[0];
+    controller.handle_client(2, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": transport_id, "producerID": producer_id}})).await;
+    assert_eq!(controller.receive_for(2, "producerConsumed").await["producerID"], producer_id);
+
+    controller.handle_client(1, json!({"setProducerAudience": {"producerID": producer_id}})).await;
+    let announced = controller.receive_for(3, "newProducers").await;
+    assert_eq!(announced, json!([{"peerID": 1, "producerID": producer_id}]));
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn transports_come_with_turn_credentials() {
+    let turn = IceServer {
+        urls: vec!["turn:192.0.2.1:3478".to_string()],
+        username: None,
+        credential: None,
+        secret: Some("coturn secret".to_string()),
+        ttl: 600,
+    };
+    let mut controller = Controller::start_with(vec![turn]).await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 4).await;
+
+    controller.handle_client(4, json!({"createTransport": {"rtpCapabilities": empty_capabilities(), "forceTCP": false, "errand": 1}})).await;
+    let created = controller.receive_for(4, "transportCreated").await;
+    let server = &created["data"]["iceServers"][0];
+    assert_eq!(server["urls"], json!(["turn:192.0.2.1:3478"]));
+
+    let username = server["username"].as_str().unwrap();
+    let (expires, user) = username.split_once(':').unwrap();
+    assert!(expires.parse::<u64>().is_ok());
+    assert_eq!(user, format!("{CHANNEL}-4"));
+    // Base64 of a 20 byte SHA-1 digest.
+    assert_eq!(server["credential"].as_str().unwrap().len(), 28);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn channel_can_be_broadcast_over_http() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    produce(&mut controller, 1, &transport_id, 20).await;
+
+    controller.send(json!({"type": "StartBroadcast", "channel": CHANNEL, "address": "127.0.0.1:0"})).await;
+    let status = controller.receive_for(usize::MAX, "broadcastStatus").await;
+    assert_eq!(status["live"], true);
+    assert_eq!(status["listeners"], 0);
+    let address = status["address"].as_str().unwrap().to_string();
+
+    let mut stream = tokio::net::TcpStream::connect(&address).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0; 1024];
+    while !received.windows(8).any(|w| w == b"OpusTags") {
+        let n = stream.read(&mut buffer).await.unwrap();
+        assert_ne!(n, 0, "stream ended early");
+        received.extend_from_slice(&buffer[..n]);
+    }
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(received.contains("Content-Type: audio/ogg\r\n"));
+    assert!(received.contains("OggS"));
+    assert!(received.contains("OpusHead"));
+    assert_eq!(controller.receive_for(usize::MAX, "broadcastStatus").await["listeners"], 1);
+
+    controller.send(json!({"type": "StopBroadcast", "channel": CHANNEL})).await;
+    let status = controller.receive_for(usize::MAX, "broadcastStatus").await;
+    assert_eq!(status, json!({"live": false, "address": null, "listeners": 0}));
+    controller.stop().await;
+}
+
+/// An RTP packet carrying 20ms of Opus silence.
+fn opus_silence(ssrc: u32, sequence: u16) -> Vec<u8> {
+    let mut packet = vec![0x80, 111];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(&(sequence as u32 * 960).to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(&[0xf8, 0xff, 0xfe]);
+    packet
+}
+
+#[tokio::test]
+async fn dial_in_peers_hear_each_other() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+
+    let phone_a = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let phone_b = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut created = vec![];
+    for (peer, phone) in [(900, &phone_a), (901, &phone_b)] {
+        controller.send(json!({
+            "type": "AddRtpPeer",
+            "channel": CHANNEL,
+            "peer": peer,
+            "remote": phone.local_addr().unwrap().to_string(),
+            "ssrc": 9000 + peer,
+        })).await;
+        let rtp_peer = controller.receive_for(usize::MAX, "rtpPeerCreated").await;
+        assert_eq!(rtp_peer["peerID"], peer);
+        created.push(rtp_peer);
+    }
+    let port_a = created[0]["tuple"]["localPort"].as_u64().unwrap() as u16;
+    let payload_type = created[1]["rtpParameters"]["codecs"][0]["payloadType"].as_u64().unwrap() as u8;
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(20));
+    let mut buffer = [0; 1500];
+    let mut sequence = 0;
+    let received = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    phone_a.send_to(&opus_silence(9900, sequence), ("127.0.0.1", port_a)).await.unwrap();
+                    sequence += 1;
+                }
+                received = phone_b.recv_from(&mut buffer) => {
+                    let (n, _) = received.unwrap();
+                    break buffer[..n].to_vec();
+                }
+            }
+        }
+    }).await.expect("the mix never reached the other phone");
+    assert_eq!(received[0] >> 6, 2);
+    assert_eq!(received[1] & 0x7f, payload_type);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn load_is_reported_to_the_controller() {
+    let mut controller = Controller::start_config(WorkerConfig {
+        load_report_interval: Some(std::time::Duration::from_millis(100)),
+        ..config()
+    }).await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    connected_transport(&mut controller, 1, 1).await;
+
+    let report = loop {
+        let (channel, peer, message) = controller.receive_any().await;
+        if let Some(report) = message.get("loadReport") {
+            assert_eq!(channel, usize::MAX);
+            assert_eq!(peer, usize::MAX);
+            if report["peers"] == 1 {
+                break report.clone();
+            }
+        }
+    };
+    assert_eq!(report["channels"], 1);
+    // The transport takes a UDP and a TCP port out of the 1000 in config().
+    assert_eq!(report["freeRtcPorts"], 998);
+    assert!(report["cpuPercent"].as_f64().unwrap() >= 0.0);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn snapshot_describes_channels_and_peers() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+    controller.send(json!({"type": "SetDeafenPeer", "channel": CHANNEL, "peer": 2, "deafen": true})).await;
+    let transport_id = connected_transport(&mut controller, 1, 1).await;
+    let producer_id = produce(&mut controller, 1, &transport_id, 3).await;
+    controller.receive_for(2, "newProducers").await;
+
+    let snapshot = controller.snapshot().await;
+    let channels = snapshot["channels"].as_array().unwrap();
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0]["channel"], CHANNEL);
+    let peers = channels[0]["peers"].as_array().unwrap();
+    assert_eq!(peers.len(), 2);
+    assert_eq!(peers[0]["peerID"], 1);
+    assert_eq!(peers[0]["deaf"], false);
+    assert_eq!(peers[0]["producers"][0]["id"], producer_id);
+    assert_eq!(peers[0]["producers"][0]["kind"], "audio");
+    assert_eq!(peers[1]["peerID"], 2);
+    assert_eq!(peers[1]["deaf"], true);
+    controller.stop().await;
+}
+
+#[cfg(feature = "impairment")]
+#[tokio::test]
+async fn impaired_producers_are_consumed_through_the_relay() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 2})).await;
+    let capabilities = controller.receive_for(2, "capabilities").await;
+    let transport_id = connected_transport(&mut controller, 1, 1).await;
+    let producer_id = produce(&mut controller, 1, &transport_id, 3).await;
+    controller.receive_for(2, "newProducers").await;
+
+    controller.send(json!({"type": "ImpairNetwork", "channel": CHANNEL, "peer": 1, "loss_percent": 20.0, "latency_ms": 80, "jitter_ms": 20})).await;
+    let announced = controller.receive_for(2, "newProducers").await;
+    assert_eq!(announced, json!([{"peerID": 1, "producerID": producer_id}]));
+
+    let receiving = connected_transport(&mut controller, 2, 4).await;
+    controller.handle_client(2, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": receiving, "producerID": producer_id}})).await;
+    let consumed = controller.receive_for(2, "producerConsumed").await;
+    assert_eq!(consumed["producerID"], producer_id);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn priority_speakers_can_be_set_and_unset() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    let transport_id = connected_transport(&mut controller, 1, 1).await;
+    produce(&mut controller, 1, &transport_id, 3).await;
+
+    for priority in [true, false, true] {
+        controller.send(json!({"type": "SetPrioritySpeaker", "channel": CHANNEL, "peer": 1, "priority": priority})).await;
+        let snapshot = controller.snapshot().await;
+        assert_eq!(snapshot["channels"][0]["peers"][0]["priority"], priority);
+    }
+    controller.stop().await;
+}
+
+/// Skips ahead to a message for the peer, for when the order of the others does not matter.
+/// Breakout rooms are never reported to the controller, so receive() asserts everything comes from CHANNEL.
+async fn skip_to(controller: &mut Controller, peer: usize, key: &str) -> Value {
+    loop {
+        let (_, to, message) = controller.receive().await;
+        if to == peer && message.get(key).is_some() {
+            return message[key].clone();
+        }
+    }
+}
+
+#[tokio::test]
+async fn breakout_rooms_split_and_merge_back() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+
+    controller.send(json!({"type": "StartBreakout", "channel": CHANNEL, "rooms": [{"room": CHANNEL + 100, "peers": [1]}]})).await;
+    assert_eq!(skip_to(&mut controller, 1, "moved").await["channel"], CHANNEL);
+    assert_eq!(skip_to(&mut controller, 1, "breakoutAssigned").await, json!({"room": CHANNEL + 100}));
+    let started = skip_to(&mut controller, 2, "breakoutStarted").await;
+    assert_eq!(started["rooms"], json!([{"room": CHANNEL + 100, "peers": [1]}]));
+
+    // Rooms can not be replaced by channels the controller places.
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL + 100, "codecs": [opus_codec()]})).await;
+    let snapshot = controller.snapshot().await;
+    assert_eq!(snapshot["channels"][1]["parent"], CHANNEL);
+    assert_eq!(snapshot["channels"][1]["peers"][0]["peerID"], 1);
+
+    controller.send(json!({"type": "EndBreakout", "channel": CHANNEL})).await;
+    assert_eq!(skip_to(&mut controller, 1, "breakoutAssigned").await, json!({"room": null}));
+    skip_to(&mut controller, 2, "breakoutEnded").await;
+
+    let snapshot = controller.snapshot().await;
+    assert_eq!(snapshot["channels"].as_array().unwrap().len(), 1);
+    assert_eq!(snapshot["channels"][0]["peers"].as_array().unwrap().len(), 2);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn producer_app_data_is_passed_on_and_kinds_filtered() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+
+    let app_data = json!({"label": "Soundboard", "source": "soundboard", "muted": false});
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    controller.handle_client(1, json!({"produceTransport": {"producerTransportID": transport_id, "kind": "audio", "rtpParameters": opus_rtp_parameters(1001), "appData": app_data, "errand": 20}})).await;
+    let producer_id = controller.receive_for(1, "transportProducing").await["producerID"].as_str().unwrap().to_string();
+    let announced = controller.receive_for(2, "newProducers").await;
+    assert_eq!(announced, json!([{"peerID": 1, "producerID": producer_id, "appData": app_data}]));
+
+    controller.handle_client(2, json!({"getProducers": {"kinds": ["video"]}})).await;
+    assert_eq!(controller.receive_for(2, "newProducers").await, json!([]));
+    controller.handle_client(2, json!({"getProducers": {"kinds": ["audio"]}})).await;
+    assert_eq!(controller.receive_for(2, "newProducers").await, announced);
+
+    let muted = json!({"label": "Soundboard", "source": "soundboard", "muted": true});
+    controller.handle_client(1, json!({"setProducerAppData": {"producerID": producer_id, "appData": muted}})).await;
+    let updated = controller.receive_for(2, "producerUpdated").await;
+    assert_eq!(updated, json!({"peerID": 1, "producerID": producer_id, "appData": muted}));
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn removed_channels_are_gone() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    controller.send(json!({"type": "RemovePeer", "channel": CHANNEL, "peer": 1})).await;
+    controller.send(json!({"type": "RemoveChannel", "channel": CHANNEL})).await;
+    assert_eq!(controller.snapshot().await["channels"], json!([]));
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn channels_are_empty_after_the_worker_is_recreated() {
+    let mut controller = Controller::start_config(WorkerConfig {
+        worker_death: WorkerDeathPolicy::Recreate,
+        ..config()
+    }).await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    let transport_id = connected_transport(&mut controller, 1, 1).await;
+    produce(&mut controller, 1, &transport_id, 3).await;
+    let rooms = json!([{"room": CHANNEL + 100, "peers": []}]);
+    controller.send(json!({"type": "StartBreakout", "channel": CHANNEL, "rooms": rooms})).await;
+    skip_to(&mut controller, 1, "breakoutStarted").await;
+
+    controller.simulate(SimulatedEvent::WorkerDied { reason: "killed by the test".to_string() });
+    let died = loop {
+        let (_, peer, message) = controller.receive_any().await;
+        if let Some(died) = message.get("workerDied") {
+            assert_eq!(peer, usize::MAX);
+            break died.clone();
+        }
+    };
+    assert_eq!(died, json!({"reason": "killed by the test", "channels": [CHANNEL], "recreated": true}));
+
+    // The breakout room went down with its peers.
+    let snapshot = controller.snapshot().await;
+    assert_eq!(snapshot["channels"].as_array().unwrap().len(), 1);
+    assert_eq!(snapshot["channels"][0]["channel"], CHANNEL);
+    assert_eq!(snapshot["channels"][0]["peers"], json!([]));
+
+    // The controller adds everyone back to the same channel, which can break out again.
+    join(&mut controller, 1).await;
+    controller.send(json!({"type": "StartBreakout", "channel": CHANNEL, "rooms": rooms})).await;
+    assert_eq!(controller.receive_for(1, "breakoutStarted").await["rooms"], rooms);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn listeners_raise_hands_and_become_speakers() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 2, "role": "listener"})).await;
+    controller.receive_for(2, "capabilities").await;
+    assert_eq!(controller.receive_for(2, "speakers").await, json!([1]));
+
+    controller.handle_client(2, json!({"raiseHand": {"raised": true}})).await;
+    assert_eq!(controller.receive_for(2, "handRaised").await, json!({"peerID": 2, "raised": true}));
+    assert_eq!(controller.receive_for(1, "handRaised").await, json!({"peerID": 2, "raised": true}));
+
+    controller.send(json!({"type": "SetPeerRole", "channel": CHANNEL, "peer": 2, "role": "speaker"})).await;
+    let mut told = vec![];
+    for _ in 0..2 {
+        let (_, to, message) = controller.receive().await;
+        assert_eq!(message["speakers"], json!([1, 2]));
+        told.push(to);
+    }
+    told.sort_unstable();
+    assert_eq!(told, [1, 2]);
+
+    let peers = controller.snapshot().await["channels"][0]["peers"].clone();
+    assert_eq!(peers[1]["role"], "speaker");
+    assert_eq!(peers[1]["handRaised"], false);
+    controller.stop().await;
+}
+
+/// Whether the consumers of the listener are paused, ordered by the producers given.
+async fn consumers_paused(controller: &mut Controller, listener: usize, producers: &[&str]) -> Vec<bool> {
+    let snapshot = controller.snapshot().await;
+    let peer = snapshot["channels"][0]["peers"].as_array().unwrap().iter()
+        .find(|peer| peer["peerID"] == listener)
+        .unwrap()
+        .clone();
+    producers.iter().map(|producer_id| {
+        let consumer = peer["consumers"].as_array().unwrap().iter()
+            .find(|consumer| consumer["producerID"] == *producer_id)
+            .unwrap();
+        consumer["paused"].as_bool().unwrap()
+    }).collect()
+}
+
+/// Every peer in the channel is told the same thing, in no particular order.
+async fn told_everyone(controller: &mut Controller, peers: usize, key: &str) -> Value {
+    let mut told = vec![];
+    for _ in 0..peers {
+        let (_, to, message) = controller.receive().await;
+        told.push((to, message[key].clone()));
+    }
+    told.sort_unstable_by_key(|(to, _)| *to);
+    let value = told[0].1.clone();
+    assert_eq!(told, (1..=peers).map(|to| (to, value.clone())).collect::<Vec<_>>());
+    value
+}
+
+#[tokio::test]
+async fn last_n_forwards_the_most_recent_speakers() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let first = produce(&mut controller, 1, &transport_id, 20).await;
+    skip_to(&mut controller, 2, "newProducers").await;
+    let transport_id = connected_transport(&mut controller, 2, 10).await;
+    let second = produce(&mut controller, 2, &transport_id, 20).await;
+    skip_to(&mut controller, 1, "newProducers").await;
+
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 3})).await;
+    let capabilities = skip_to(&mut controller, 3, "capabilities").await;
+    let receiving = connected_transport(&mut controller, 3, 10).await;
+    for producer_id in [&first, &second] {
+        controller.handle_client(3, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": receiving, "producerID": producer_id}})).await;
+        assert_eq!(controller.receive_for(3, "producerConsumed").await["producerID"], *producer_id);
+    }
+    let producers = [first.as_str(), second.as_str()];
+
+    // Nobody has spoken yet, so nobody is paused.
+    controller.send(json!({"type": "SetLastN", "channel": CHANNEL, "last_n": 1})).await;
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [false, false]);
+
+    controller.simulate(SimulatedEvent::DominantSpeaker { channel: CHANNEL, producer_id: first.clone() });
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([1]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [false, true]);
+
+    controller.simulate(SimulatedEvent::DominantSpeaker { channel: CHANNEL, producer_id: second.clone() });
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([2]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [true, false]);
+
+    controller.send(json!({"type": "SetLastN", "channel": CHANNEL, "last_n": null})).await;
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!(null));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [false, false]);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn profiles_pick_codecs_and_bitrate_caps_can_be_lifted() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "profile": "voice"})).await;
+    // Neither codecs nor a profile make a channel nobody could produce in.
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL + 1})).await;
+    let channels = controller.snapshot().await["channels"].clone();
+    assert_eq!(channels.as_array().unwrap().len(), 1);
+    let channel = channels[0].clone();
+    let codecs = channel["codecs"].as_array().unwrap();
+    assert_eq!(codecs.len(), 1);
+    assert_eq!(codecs[0]["mimeType"], "audio/opus");
+    assert_eq!(codecs[0]["parameters"]["maxaveragebitrate"], 32000);
+    assert_eq!(channel["bitrate"], json!({"maxIncomingBitrate": 64000, "maxOutgoingBitrate": null, "initialAvailableOutgoingBitrate": 300000}));
+
+    join(&mut controller, 1).await;
+    connected_transport(&mut controller, 1, 1).await;
+    controller.send(json!({"type": "StartBreakout", "channel": CHANNEL, "rooms": [{"room": CHANNEL + 100, "peers": [1]}]})).await;
+    skip_to(&mut controller, 1, "breakoutStarted").await;
+    controller.send(json!({"type": "SetChannelBitrate", "channel": CHANNEL, "bitrate": {"maxIncomingBitrate": null, "maxOutgoingBitrate": 500000}})).await;
+    let channels = controller.snapshot().await["channels"].clone();
+    // Breakout rooms follow the channel they broke out of.
+    for channel in channels.as_array().unwrap() {
+        assert_eq!(channel["bitrate"], json!({"maxIncomingBitrate": null, "maxOutgoingBitrate": 500000, "initialAvailableOutgoingBitrate": null}));
+    }
+    assert_eq!(channels[1]["peers"][0]["peerID"], 1);
+
+    // Transports created after lifting the caps are not capped either.
+    connected_transport(&mut controller, 1, 3).await;
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn restarting_ice_hands_out_new_credentials() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    controller.handle_client(1, json!({"createTransport": {"rtpCapabilities": empty_capabilities(), "forceTCP": false, "errand": 1}})).await;
+    let created = controller.receive_for(1, "transportCreated").await;
+    let transport_id = created["data"]["id"].as_str().unwrap();
+    let before = created["data"]["iceParameters"].clone();
+
+    controller.handle_client(1, json!({"restartIce": {"transportID": transport_id, "errand": 2}})).await;
+    let restarted = controller.receive_for(1, "iceRestarted").await;
+    assert_eq!(restarted["errand"], 2);
+    let after = &restarted["iceParameters"];
+    assert!(after["usernameFragment"].is_string());
+    assert_ne!(after["usernameFragment"], before["usernameFragment"]);
+    assert_ne!(after["password"], before["password"]);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn moved_peers_take_their_producers_along() {
+    const OTHER: usize = CHANNEL + 1;
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    controller.send(json!({"type": "NewChannel", "channel": OTHER, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+    controller.send(json!({"type": "AddPeer", "channel": OTHER, "peer": 3})).await;
+    let (channel, peer, message) = controller.receive_any().await;
+    assert_eq!((channel, peer), (OTHER, 3));
+    assert!(message.get("capabilities").is_some());
+
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let producer_id = produce(&mut controller, 1, &transport_id, 20).await;
+    controller.receive_for(2, "newProducers").await;
+
+    controller.send(json!({"type": "MovePeer", "from": CHANNEL, "to": OTHER, "peer": 1})).await;
+    let announced = json!([{"peerID": 1, "producerID": producer_id}]);
+    let expected = [
+        (CHANNEL, 2, json!({"producerClosed": {"peerID": 1, "producerID": producer_id}})),
+        (CHANNEL, 2, json!({"peerLeft": 1})),
+    ];
+    for expected in expected {
+        assert_eq!(controller.receive_any().await, expected);
+    }
+    let (channel, peer, message) = controller.receive_any().await;
+    assert_eq!((channel, peer, &message["moved"]["channel"]), (OTHER, 1, &json!(OTHER)));
+    assert_eq!(controller.receive_any().await, (OTHER, 1, json!({"newProducers": []})));
+    assert_eq!(controller.receive_any().await, (OTHER, 3, json!({"newProducers": announced})));
+
+    // The client still talks through the channel it joined, which leads to where it is now.
+    controller.handle_client(1, json!({"getProducers": {}})).await;
+    assert_eq!(controller.receive_any().await, (OTHER, 1, json!({"newProducers": []})));
+
+    let channels = controller.snapshot().await["channels"].clone();
+    assert_eq!(channels[0]["peers"].as_array().unwrap().len(), 1);
+    assert_eq!(channels[0]["peers"][0]["peerID"], 2);
+    let peers = channels[1]["peers"].as_array().unwrap();
+    assert_eq!(peers.len(), 2);
+    assert_eq!(peers[0]["peerID"], 1);
+    assert_eq!(peers[0]["producers"][0]["id"], producer_id);
+    controller.stop().await;
+}
+
+#[cfg(feature = "impairment")]
+#[tokio::test]
+async fn impaired_producers_follow_last_n_and_close_with_the_original() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let first = produce(&mut controller, 1, &transport_id, 20).await;
+    skip_to(&mut controller, 2, "newProducers").await;
+    let transport_id = connected_transport(&mut controller, 2, 10).await;
+    let second = produce(&mut controller, 2, &transport_id, 20).await;
+    skip_to(&mut controller, 1, "newProducers").await;
+
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 3})).await;
+    let capabilities = skip_to(&mut controller, 3, "capabilities").await;
+    let receiving = connected_transport(&mut controller, 3, 10).await;
+    controller.send(json!({"type": "ImpairNetwork", "channel": CHANNEL, "peer": 1, "loss_percent": 20.0, "latency_ms": 80})).await;
+    let mut told = vec![];
+    while told.len() < 2 {
+        let (_, to, message) = controller.receive().await;
+        if message.get("newProducers").is_some() {
+            told.push(to);
+        }
+    }
+    for producer_id in [&first, &second] {
+        controller.handle_client(3, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": receiving, "producerID": producer_id}})).await;
+        assert_eq!(controller.receive_for(3, "producerConsumed").await["producerID"], *producer_id);
+    }
+    let producers = [first.as_str(), second.as_str()];
+
+    // The relayed producer counts as its original speaker.
+    controller.send(json!({"type": "SetLastN", "channel": CHANNEL, "last_n": 1})).await;
+    controller.simulate(SimulatedEvent::DominantSpeaker { channel: CHANNEL, producer_id: first.clone() });
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([1]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [false, true]);
+    controller.simulate(SimulatedEvent::DominantSpeaker { channel: CHANNEL, producer_id: second.clone() });
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([2]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [true, false]);
+
+    // Closing the original closes whatever was consumed through its relay.
+    controller.handle_client(1, json!({"producerClosed": {"producerID": first}})).await;
+    skip_to(&mut controller, 3, "consumerClosed").await;
+    let peers = controller.snapshot().await["channels"][0]["peers"].clone();
+    assert_eq!(peers[2]["consumers"].as_array().unwrap().len(), 1);
+    assert_eq!(peers[2]["consumers"][0]["producerID"], second);
+    controller.stop().await;
+}
+
+#[tokio::test]
+async fn priority_speakers_duck_the_rest_and_take_the_first_slots() {
+    let mut controller = Controller::start().await;
+    controller.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": [opus_codec()]})).await;
+    join(&mut controller, 1).await;
+    join(&mut controller, 2).await;
+    let transport_id = connected_transport(&mut controller, 1, 10).await;
+    let first = produce(&mut controller, 1, &transport_id, 20).await;
+    skip_to(&mut controller, 2, "newProducers").await;
+    let transport_id = connected_transport(&mut controller, 2, 10).await;
+    let second = produce(&mut controller, 2, &transport_id, 20).await;
+    skip_to(&mut controller, 1, "newProducers").await;
+
+    controller.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 3})).await;
+    let capabilities = skip_to(&mut controller, 3, "capabilities").await;
+    let receiving = connected_transport(&mut controller, 3, 10).await;
+    for producer_id in [&first, &second] {
+        controller.handle_client(3, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": receiving, "producerID": producer_id}})).await;
+        assert_eq!(controller.receive_for(3, "producerConsumed").await["producerID"], *producer_id);
+    }
+    let producers = [first.as_str(), second.as_str()];
+
+    controller.send(json!({"type": "SetPrioritySpeaker", "channel": CHANNEL, "peer": 1, "priority": true})).await;
+    controller.send(json!({"type": "SetLastN", "channel": CHANNEL, "last_n": 1})).await;
+    controller.snapshot().await;
+    controller.simulate(SimulatedEvent::DominantSpeaker { channel: CHANNEL, producer_id: second.clone() });
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([2]));
+
+    // Speaking up takes the only slot from whoever spoke last.
+    controller.simulate(SimulatedEvent::PriorityVolumes { channel: CHANNEL, producer_ids: vec![first.clone()] });
+    assert_eq!(told_everyone(&mut controller, 3, "duck").await, json!({"factor": 0.3, "speakers": [1]}));
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([1]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [false, true]);
+
+    // Ordinary speakers do not count, so this is as good as silence.
+    controller.simulate(SimulatedEvent::PriorityVolumes { channel: CHANNEL, producer_ids: vec![second.clone()] });
+    assert_eq!(told_everyone(&mut controller, 3, "duck").await, json!({"factor": 1.0, "speakers": []}));
+    assert_eq!(told_everyone(&mut controller, 3, "forwardedSpeakers").await, json!([2]));
+    assert_eq!(consumers_paused(&mut controller, 3, &producers).await, [true, false]);
+    controller.stop().await;
+}
@@ -0,0 +1,46 @@
+//! The forms of `SFU_CONTROLLER_URL` a media worker understands.
+
+use std::path::PathBuf;
+
+use media_worker_sfu::controller_link::ControllerUrl;
+
+#[test]
+fn unix_socket_urls() {
+    let url: ControllerUrl = "ws+unix:///run/controller.sock:/media-worker/0/code".parse().unwrap();
+    assert_eq!(url, ControllerUrl::Unix {
+        socket: PathBuf::from("/run/controller.sock"),
+        request: "ws://localhost/media-worker/0/code".to_string(),
+    });
+}
+
+#[test]
+fn legacy_unix_socket_urls() {
+    let url: ControllerUrl = "ws://unix/media-worker/0/code:/run/controller.sock".parse().unwrap();
+    assert_eq!(url, ControllerUrl::Unix {
+        socket: PathBuf::from("/run/controller.sock"),
+        request: "ws://unix/media-worker/0/code".to_string(),
+    });
+}
+
+#[test]
+fn tcp_urls() {
+    for s in ["ws://localhost:8080/media-worker/0/code", "wss://controller.example:443/media-worker/1/code"] {
+        let url: ControllerUrl = s.parse().unwrap();
+        assert!(matches!(url, ControllerUrl::Tcp(url) if url.as_str() == s));
+    }
+}
+
+#[test]
+fn bad_urls_are_errors() {
+    for s in [
+        "",
+        "localhost:8080",
+        "http://localhost/media-worker/0/code",
+        "ws+unix:///run/controller.sock",
+        "ws+unix://:/media-worker/0/code",
+        "ws+unix:///run/controller.sock:media-worker",
+        "ws://unix/media-worker/0/code",
+    ] {
+        assert!(s.parse::<ControllerUrl>().is_err(), "{s} should not parse");
+    }
+}
@@ -0,0 +1,170 @@
+//! Uses the SFU as a library, linked to the test through plain channels.
+
+use media_worker_sfu::{Sfu, SfuHandle, WorkerConfig, WorkerDeathPolicy, WorkerLogLevel};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+const CHANNEL: usize = 1;
+
+fn opus_codecs() -> Value {
+    json!([{"kind": "audio", "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []}])
+}
+
+fn config() -> WorkerConfig {
+    WorkerConfig {
+        log_level: WorkerLogLevel::Warn,
+        log_tags: vec![],
+        rtc_port_range: 41000..=41999,
+        listen_ip: "127.0.0.1".parse().unwrap(),
+        announce_ip: "192.0.2.1".parse().unwrap(),
+        ice_servers: vec![],
+        worker_death: WorkerDeathPolicy::Exit,
+        load_report_interval: None,
+        snapshot_file: None,
+    }
+}
+
+/// The controller side of an SFU attached through plain channels.
+struct Link {
+    to_sfu: mpsc::UnboundedSender<String>,
+    from_sfu: mpsc::UnboundedReceiver<String>,
+    handle: SfuHandle,
+}
+
+impl Link {
+    async fn start() -> Link {
+        Link::attach(Sfu::builder(config()).build().await.unwrap())
+    }
+
+    fn attach(sfu: Sfu) -> Link {
+        let (to_sfu, from_controller) = mpsc::unbounded_channel::<String>();
+        let (to_controller, from_sfu) = mpsc::unbounded_channel::<String>();
+        let read = Box::pin(futures_util::stream::unfold(from_controller, |mut rx| async move {
+            rx.recv().await.map(|message| (message, rx))
+        }));
+        let write = Box::pin(futures_util::sink::unfold(to_controller, |tx, message: String| async move {
+            tx.send(message)?;
+            Ok::<_, anyhow::Error>(tx)
+        }));
+        Link {
+            to_sfu,
+            from_sfu,
+            handle: sfu.attach(write, read),
+        }
+    }
+
+    fn send(&self, message: Value) {
+        self.to_sfu.send(message.to_string()).unwrap();
+    }
+
+    fn handle_client(&self, peer: usize, message: Value) {
+        self.send(json!({"type": "HandleClient", "channel": CHANNEL, "peer": peer, "message": message}));
+    }
+
+    /// Waits for the next message to a peer, which has to be of the given kind.
+    async fn receive_for(&mut self, peer: usize, key: &str) -> Value {
+        let reply = tokio::time::timeout(std::time::Duration::from_secs(5), self.from_sfu.recv())
+            .await
+            .expect("timed out waiting for the SFU")
+            .expect("SFU closed the link");
+        let (_, to, message): (usize, usize, Value) = serde_json::from_str(&reply).unwrap();
+        assert_eq!(to, peer, "unexpected recipient for {message}");
+        message.get(key).unwrap_or_else(|| panic!("expected {key}, got {message}")).clone()
+    }
+
+    /// Waits until the SFU handled everything sent before.
+    async fn settle(&mut self) {
+        self.send(json!({"type": "Snapshot"}));
+        self.receive_for(usize::MAX, "snapshot").await;
+    }
+
+    /// Adds a peer with a transport, returning its capabilities and the transport ID.
+    async fn join(&mut self, peer: usize) -> (Value, String) {
+        self.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": peer}));
+        let capabilities = self.receive_for(peer, "capabilities").await;
+        self.handle_client(peer, json!({"createTransport": {"rtpCapabilities": capabilities, "forceTCP": false, "errand": 1}}));
+        let transport_id = self.receive_for(peer, "transportCreated").await["data"]["id"].as_str().unwrap().to_string();
+        (capabilities, transport_id)
+    }
+
+    /// Adds a peer producing audio, returning the IDs of its transport and producer.
+    async fn join_producing(&mut self, peer: usize) -> (String, String) {
+        let (_, transport_id) = self.join(peer).await;
+        let rtp_parameters = json!({
+            "mid": "0",
+            "codecs": [{"mimeType": "audio/opus", "payloadType": 111, "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []}],
+            "headerExtensions": [],
+            "encodings": [{"ssrc": 1000 + peer}],
+            "rtcp": {"cname": format!("peer-{peer}"), "reducedSize": true},
+        });
+        self.handle_client(peer, json!({"produceTransport": {"producerTransportID": transport_id, "kind": "audio", "rtpParameters": rtp_parameters, "errand": 2}}));
+        let producer_id = self.receive_for(peer, "transportProducing").await["producerID"].as_str().unwrap().to_string();
+        (transport_id, producer_id)
+    }
+
+    /// Adds a peer consuming a producer, returning the ID of its consumer.
+    async fn join_consuming(&mut self, peer: usize, producer_id: &str) -> String {
+        let (capabilities, transport_id) = self.join(peer).await;
+        self.handle_client(peer, json!({"consumeProducer": {"rtpCapabilities": capabilities, "consumerTransportID": transport_id, "producerID": producer_id}}));
+        self.receive_for(peer, "producerConsumed").await["id"].as_str().unwrap().to_string()
+    }
+}
+
+#[tokio::test]
+async fn stats_follow_controller_messages() {
+    let sfu = Sfu::builder(config())
+        .lifecycle_hook(|level, line| eprintln!("[{level}] {line}"))
+        .build()
+        .await
+        .unwrap();
+    let mut link = Link::attach(sfu);
+    link.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": opus_codecs()}));
+    link.send(json!({"type": "AddPeer", "channel": CHANNEL, "peer": 5}));
+    link.receive_for(5, "capabilities").await;
+
+    let stats = link.handle.stats().await.unwrap();
+    assert_eq!(stats.channels, 1);
+    assert_eq!(stats.peers, 1);
+    assert_eq!(stats.transports, 0);
+    link.handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closing_a_transport_closes_what_was_created_on_it() {
+    let mut link = Link::start().await;
+    link.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": opus_codecs()}));
+    let (transport_id, producer_id) = link.join_producing(1).await;
+    let consumer_id = link.join_consuming(2, &producer_id).await;
+
+    let stats = link.handle.stats().await.unwrap();
+    assert_eq!((stats.transports, stats.producers, stats.consumers), (2, 1, 1));
+
+    link.send(json!({"type": "RemoveTransport", "channel": CHANNEL, "peer": 1, "transport_id": transport_id}));
+    assert_eq!(link.receive_for(2, "consumerClosed").await, json!(consumer_id));
+    assert_eq!(link.receive_for(2, "producerClosed").await, json!({"peerID": 1, "producerID": producer_id}));
+
+    let stats = link.handle.stats().await.unwrap();
+    assert_eq!((stats.peers, stats.transports, stats.producers, stats.consumers), (2, 1, 0, 0));
+    link.handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn leaving_peers_take_everything_with_them() {
+    let mut link = Link::start().await;
+    link.send(json!({"type": "NewChannel", "channel": CHANNEL, "codecs": opus_codecs()}));
+    let (_, producer_id) = link.join_producing(1).await;
+    let consumer_id = link.join_consuming(2, &producer_id).await;
+
+    link.send(json!({"type": "RemovePeer", "channel": CHANNEL, "peer": 1}));
+    assert_eq!(link.receive_for(2, "consumerClosed").await, json!(consumer_id));
+    assert_eq!(link.receive_for(2, "producerClosed").await, json!({"peerID": 1, "producerID": producer_id}));
+    assert_eq!(link.receive_for(2, "peerLeft").await, json!(1));
+    let stats = link.handle.stats().await.unwrap();
+    assert_eq!((stats.peers, stats.transports, stats.producers, stats.consumers), (1, 1, 0, 0));
+
+    link.send(json!({"type": "RemovePeer", "channel": CHANNEL, "peer": 2}));
+    link.settle().await;
+    let stats = link.handle.stats().await.unwrap();
+    assert_eq!((stats.channels, stats.peers, stats.transports, stats.producers, stats.consumers), (1, 0, 0, 0, 0));
+    link.handle.shutdown().await.unwrap();
+}
@@ -0,0 +1,95 @@
+//! Connects to the SFU the way a browser does in standalone mode.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use media_worker_sfu::standalone::{sign_token, Standalone, StandaloneConfig};
+use media_worker_sfu::{Sfu, WorkerConfig, WorkerDeathPolicy, WorkerLogLevel};
+use tokio_tungstenite::tungstenite::Message;
+
+const SECRET: &[u8] = b"not so secret";
+
+async fn start() -> (std::net::SocketAddr, Arc<Standalone>) {
+    let config = WorkerConfig {
+        log_level: WorkerLogLevel::Warn,
+        log_tags: vec![],
+        rtc_port_range: 42000..=42999,
+        listen_ip: "127.0.0.1".parse().unwrap(),
+        announce_ip: "192.0.2.1".parse().unwrap(),
+        ice_servers: vec![],
+        worker_death: WorkerDeathPolicy::Exit,
+        load_report_interval: None,
+        snapshot_file: None,
+    };
+    let standalone = StandaloneConfig {
+        secret: SECRET.to_vec(),
+        codecs: serde_json::from_str(r#"[{"kind": "audio", "mimeType": "audio/opus", "clockRate": 48000, "channels": 2, "parameters": {}, "rtcpFeedback": []}]"#).unwrap(),
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let sfu = Sfu::builder(config).build().await.unwrap();
+    let standalone = Arc::new(Standalone::attach(sfu, standalone));
+    let serving = standalone.clone();
+    tokio::spawn(async move { serving.serve(listener).await });
+    (address, standalone)
+}
+
+/// Waits for the SFU to hold the given number of channels.
+async fn wait_for_channels(standalone: &Standalone, channels: usize) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while standalone.handle().stats().await.unwrap().channels != channels {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap_or_else(|_| panic!("SFU never got to {channels} channels"));
+}
+
+fn far_future() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600
+}
+
+#[tokio::test]
+async fn browser_with_valid_token_gets_capabilities() {
+    let (address, _) = start().await;
+    let token = sign_token(SECRET, 3, 9, far_future());
+    let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{address}/?token={token}")).await.unwrap();
+
+    let Some(Ok(Message::Text(text))) = ws.next().await else {
+        panic!("expected a text message");
+    };
+    let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert!(message.get("capabilities").is_some(), "got {message}");
+}
+
+#[tokio::test]
+async fn browser_with_bad_token_is_refused() {
+    let (address, _) = start().await;
+
+    let forged = sign_token(b"some other secret", 3, 9, far_future());
+    assert!(tokio_tungstenite::connect_async(format!("ws://{address}/?token={forged}")).await.is_err());
+
+    let expired = sign_token(SECRET, 3, 9, 1);
+    assert!(tokio_tungstenite::connect_async(format!("ws://{address}/?token={expired}")).await.is_err());
+
+    assert!(tokio_tungstenite::connect_async(format!("ws://{address}/")).await.is_err());
+}
+
+#[tokio::test]
+async fn channel_is_recreated_after_the_last_browser_left() {
+    let (address, standalone) = start().await;
+    for peer in [9, 10] {
+        let token = sign_token(SECRET, 3, peer, far_future());
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{address}/?token={token}")).await.unwrap();
+        let Some(Ok(Message::Text(text))) = ws.next().await else {
+            panic!("expected a text message");
+        };
+        let message: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert!(message.get("capabilities").is_some(), "got {message}");
+        assert_eq!(standalone.handle().stats().await.unwrap().channels, 1);
+
+        ws.close(None).await.unwrap();
+        while ws.next().await.is_some() {}
+        // Gone before the next browser brings it back.
+        wait_for_channels(&standalone, 0).await;
+    }
+}
@@ -1,4 +1,6 @@
 import { spawn, ChildProcess } from "node:child_process";
+import { timingSafeEqual } from "node:crypto";
+import { join } from "node:path";
 
 import { lastModifiedPosition, scheduleSync } from "./last_modified.js";
 
@@ -9,10 +11,10 @@ import { lastModifiedPosition, scheduleSync } from "./last_modified.js";
 import { db } from "./db.js";
 
 /**
- * @import { VoiceState, MessageToSFU, MessageFromSFU, UpdateObjectVariants } from './schema.ts'
+ * @import { VoiceState, MessageToSFU, MessageFromSFU, UpdateObjectVariants, SFUSnapshot } from './schema.ts'
  */
 
-import { sfuToServer } from "./schema.ts";
+import { sfuToServer, mediaWorkerRegistration } from "./schema.ts";
 
 import { randomString } from "./auth.js";
 
@@ -30,6 +32,14 @@ const { info, error } = getLog("channels");
 
 // TODO: Maybe we can move activeChannel to its own file someday?
 
+/**
+ * @typedef {{
+ *  live: boolean,
+ *  address: string | null,
+ *  listeners: number,
+ * }} BroadcastStatus
+ */
+
 /**
  * @typedef {{
  *  workerIndex: number,
@@ -37,6 +47,7 @@ const { info, error } = getLog("channels");
  *  connectedUsers: VoiceState[]
  *  lastModified: number,
  *  faddishness: number,
+ *  broadcast?: BroadcastStatus,
  * }} ActiveChannel
  */
 
@@ -57,6 +68,21 @@ export function setClientMessageCallback(cb) {
 
 const decoder = new TextDecoder();
 
+// Who the SFU addresses messages meant for us rather than a peer to. It is usize::MAX
+// over there, which JSON.parse() rounds to 2^64.
+const CONTROLLER = 2 ** 64;
+
+/**
+ * @typedef {{
+ *  channels: number,
+ *  peers: number,
+ *  producers: number,
+ *  consumers: number,
+ *  cpuPercent: number,
+ *  freeRtcPorts: number,
+ * }} MediaWorkerLoad
+ */
+
 /**
  * @typedef {{
  *  code: string,
@@ -65,6 +91,12 @@ const decoder = new TextDecoder();
  *  ws?: WebSocket,
  *  index: number,
  *  queue: string[],
+ *  load?: MediaWorkerLoad,
+ *  remote?: {
+ *      region: string,
+ *      capacity: number,
+ *      announceIPs: string[],
+ *  },
  * }} GenericMediaWorker
  */
 
@@ -98,6 +130,8 @@ function resetWorker(worker) {
 
     worker.state = "disconnected";
 
+    worker.load = undefined;
+
     worker.code = randomString();
 
     if (worker.process) {
@@ -145,11 +179,21 @@ export function mediaWorkerWebSocketConnected(ws, index, code) {
         return;
     }
 
+    attachMediaWorker(worker, ws);
+}
+
+/**
+ * @param {GenericMediaWorker} worker
+ * @param {WebSocket} ws
+ */
+function attachMediaWorker(worker, ws) {
     worker.ws = ws;
     worker.state = "connected";
     flushMediaWorkerQueue(worker);
+    // Answered once the queued messages are handled, so both sides should agree by then.
+    sendMediaMessageToWorker(worker, `${JSON.stringify({ type: "Snapshot" })}\n`);
     ws.on("error", function (e) {
-        error("websocket for worker", index, "had the following error:", e);
+        error("websocket for worker", worker.index, "had the following error:", e);
     });
     ws.on("message", function (event) {
         if (event.data === "heartbeat") {
@@ -157,22 +201,318 @@ export function mediaWorkerWebSocketConnected(ws, index, code) {
             return;
         }
         const msg = sfuToServer.parse(JSON.parse(decoder.decode(event)));
+        if (msg[2].loadReport !== undefined) {
+            updateLoad(worker, msg[2].loadReport);
+            return;
+        }
+        if (msg[2].snapshot !== undefined) {
+            diffSnapshot(worker, msg[2].snapshot, "media worker");
+            return;
+        }
+        if (msg[2].previousSnapshot !== undefined) {
+            diffSnapshot(worker, msg[2].previousSnapshot, "previous media worker");
+            return;
+        }
+        if (msg[1] === CONTROLLER) {
+            handleControllerMessage(worker, msg[0], msg[2]);
+            return;
+        }
         info("websocket:", msg);
         clientMessageCallback(msg[0], msg[1], msg[2]);
     });
     ws.on("close", function () {
+        // The worker may have been reset and reconnected since.
+        if (worker.ws !== ws) {
+            return;
+        }
         error("mediaWorker lost connection unexpectedly");
         resetWorker(worker);
+        replaceChannelsOf(worker);
     });
 }
 
+/**
+ * Handles what a media worker tells us about a channel rather than one of its peers.
+ * @param {GenericMediaWorker} worker
+ * @param {number} channelID
+ * @param {MessageFromSFU} message
+ */
+function handleControllerMessage(worker, channelID, message) {
+    if (message.producerTrace !== undefined) {
+        const { peerID, producerID, event } = message.producerTrace;
+        info("trace of producer", producerID, "of peer", peerID, "in channel", channelID, event);
+    } else if (message.broadcastStatus !== undefined) {
+        updateBroadcast(channelID, message.broadcastStatus);
+    } else if (message.workerDied !== undefined) {
+        const { reason, channels, recreated } = message.workerDied;
+        error("media worker", worker.index, "lost its mediasoup worker:", reason);
+        if (recreated) {
+            rejoinChannels(worker, channels);
+        } else {
+            // It exits on its own, but there is no point waiting for that.
+            resetWorker(worker);
+            replaceChannelsOf(worker);
+        }
+    } else if (message.rtpPeerCreated !== undefined) {
+        const key = `${channelID}:${message.rtpPeerCreated.peerID}`;
+        const pending = pendingRtpPeers.get(key);
+        pendingRtpPeers.delete(key);
+        if (pending === undefined) {
+            info("unexpected RTP peer", message.rtpPeerCreated.peerID, "in channel", channelID);
+            return;
+        }
+        clearTimeout(pending.timeout);
+        pending.resolve(message.rtpPeerCreated);
+    } else {
+        error("media worker", worker.index, "sent an unexpected message:", message);
+    }
+}
+
+/**
+ * Adds the users of channels that a worker brought back up empty to them again.
+ * @param {GenericMediaWorker} worker
+ * @param {number[]} channelIDs
+ */
+function rejoinChannels(worker, channelIDs) {
+    for (const channelID of channelIDs) {
+        const channel = activeChannels.get(channelID);
+        if (channel === undefined || channel.workerIndex !== worker.index) {
+            continue;
+        }
+        channel.broadcast = undefined;
+        for (const voiceState of channel.connectedUsers) {
+            sendMediaMessage(worker.index, {
+                type: "AddPeer",
+                channel: channelID,
+                peer: voiceState.peerID,
+            });
+            synchronizeVoiceState(voiceState, true);
+        }
+    }
+}
+
+/**
+ * Places the channels of a worker that went away on another one, taking their users along.
+ * @param {GenericMediaWorker} worker
+ */
+function replaceChannelsOf(worker) {
+    for (const channel of [...activeChannels.values()]) {
+        if (channel.workerIndex !== worker.index) {
+            continue;
+        }
+        activeChannels.delete(channel.channelID);
+        /** @type {ActiveChannel} */
+        let replaced;
+        try {
+            replaced = getActiveChannel(channel.channelID);
+        } catch (e) {
+            error("could not place channel", channel.channelID, "again:", e);
+            for (const voiceState of channel.connectedUsers) {
+                voiceState.channelID = -1;
+            }
+            scheduleSync();
+            continue;
+        }
+        info("moved channel", channel.channelID, "from media worker", worker.index, "to", replaced.workerIndex);
+        for (const voiceState of channel.connectedUsers) {
+            replaced.connectedUsers.push(voiceState);
+            sendMediaMessage(replaced.workerIndex, {
+                type: "AddPeer",
+                channel: replaced.channelID,
+                peer: voiceState.peerID,
+            });
+            synchronizeVoiceState(voiceState, true);
+        }
+    }
+}
+
+/**
+ * @param {number} channelID
+ * @param {BroadcastStatus} status
+ */
+function updateBroadcast(channelID, status) {
+    const channel = activeChannels.get(channelID);
+    if (channel === undefined) {
+        return;
+    }
+    const wasLive = channel.broadcast?.live ?? false;
+    if (status.live && !wasLive) {
+        info("channel", channelID, "is broadcasting at", status.address);
+    } else if (!status.live && wasLive) {
+        info("channel", channelID, "stopped broadcasting");
+    }
+    channel.broadcast = status.live ? status : undefined;
+}
+
+/**
+ * @param {GenericMediaWorker} worker
+ * @param {MediaWorkerLoad} load
+ */
+function updateLoad(worker, load) {
+    const threshold = config.mediaWorker.worker.freePortsWarning;
+    const wasLow = worker.load !== undefined && worker.load.freeRtcPorts < threshold;
+    if (load.freeRtcPorts < threshold && !wasLow) {
+        error("media worker", worker.index, "is running out of RTC ports,", load.freeRtcPorts, "left");
+    }
+    worker.load = load;
+}
+
+/**
+ * Logs everywhere the channels a worker holds disagree with the channels placed on it.
+ * @param {GenericMediaWorker} worker
+ * @param {SFUSnapshot} snapshot
+ * @param {string} holder
+ */
+function diffSnapshot(worker, snapshot, holder) {
+    const held = new Map(snapshot.channels.filter((channel) => channel.parent == null).map((channel) => [channel.channel, channel]));
+    // Peers in breakout rooms are still in the channel they broke out of as far as we know.
+    for (const room of snapshot.channels) {
+        const parent = room.parent == null ? undefined : held.get(room.parent);
+        if (parent !== undefined) {
+            parent.peers.push(...room.peers);
+        }
+    }
+    for (const channel of activeChannels.values()) {
+        if (channel.workerIndex !== worker.index) {
+            continue;
+        }
+        const heldChannel = held.get(channel.channelID);
+        held.delete(channel.channelID);
+        if (heldChannel === undefined) {
+            error(holder, worker.index, "does not hold channel", channel.channelID);
+            continue;
+        }
+        // Dial-in peers are only known to the SFU.
+        const peers = new Map(heldChannel.peers.filter((peer) => !peer.rtp).map((peer) => [peer.peerID, peer]));
+        for (const voiceState of channel.connectedUsers) {
+            const peer = peers.get(voiceState.peerID);
+            peers.delete(voiceState.peerID);
+            if (peer === undefined) {
+                error(holder, worker.index, "does not hold peer", voiceState.peerID, "in channel", channel.channelID);
+            } else if (peer.deaf !== voiceState.selfDeafen) {
+                error(holder, worker.index, "has peer", voiceState.peerID, "deafened:", peer.deaf, "instead of", voiceState.selfDeafen);
+            }
+        }
+        for (const peerID of peers.keys()) {
+            error(holder, worker.index, "holds unknown peer", peerID, "in channel", channel.channelID);
+        }
+    }
+    for (const channelID of held.keys()) {
+        error(holder, worker.index, "holds channel", channelID, "which is not placed on it");
+    }
+}
+
+/**
+ * @param {string} token
+ */
+function validRemoteToken(token) {
+    const expected = Buffer.from(config.mediaWorker.remoteToken);
+    const given = Buffer.from(token);
+    return expected.length > 0 && expected.length === given.length && timingSafeEqual(expected, given);
+}
+
+const REGISTRATION_TIMEOUT_MS = 10000;
+
+/**
+ * Called for media workers that were started on their own and dialed in, rather than
+ * being spawned by getMediaWorker(). They introduce themselves with their first message.
+ * @param {WebSocket} ws
+ */
+export function mediaWorkerRegistering(ws) {
+    // Anyone can connect before the token is checked, so silent sockets are not kept around.
+    const timeout = setTimeout(function () {
+        error("remote sfu did not register in time");
+        try {
+            ws.close();
+        } catch (_) {}
+    }, REGISTRATION_TIMEOUT_MS);
+    ws.once("close", function () {
+        clearTimeout(timeout);
+    });
+    ws.once("message", function (event) {
+        clearTimeout(timeout);
+        let json;
+        try {
+            json = JSON.parse(decoder.decode(event));
+        } catch (_) {
+            // Errors thrown in here would take down the whole controller.
+        }
+        const parsed = mediaWorkerRegistration.safeParse(json);
+        if (!parsed.success || !validRemoteToken(parsed.data.register.token)) {
+            error("failed remote sfu registration");
+            try {
+                ws.close();
+            } catch (_) {}
+            return;
+        }
+        const { region, capacity, announceIPs } = parsed.data.register;
+
+        // Slots of remote workers that went away are reused, channels refer to workers by index.
+        let worker = mediaWorkers.find((w) => w.remote !== undefined && w.state === "disconnected");
+        if (worker === undefined) {
+            worker = {
+                code: "",
+                state: "disconnected",
+                process: undefined,
+                ws: undefined,
+                index: mediaWorkers.length,
+                queue: [],
+            };
+            mediaWorkers.push(worker);
+        }
+        worker.remote = { region, capacity, announceIPs };
+        info("remote media worker", worker.index, "registered from region", region, "with capacity", capacity);
+        attachMediaWorker(worker, ws);
+    });
+}
+
+/**
+ * @param {GenericMediaWorker} worker
+ */
+function peersOnWorker(worker) {
+    let peers = 0;
+    for (const channel of activeChannels.values()) {
+        if (channel.workerIndex === worker.index) {
+            peers += channel.connectedUsers.length;
+        }
+    }
+    return peers;
+}
+
+/**
+ * Remote workers can not be started from here, so they are only picked while connected
+ * and below the capacity they announced.
+ * @param {GenericMediaWorker} worker
+ */
+function canPlaceOn(worker) {
+    if (worker.remote === undefined) {
+        return true;
+    }
+    return worker.state === "connected" && peersOnWorker(worker) < worker.remote.capacity;
+}
+
+/**
+ * Reports lag behind, so peers that joined since the last one are counted here as well.
+ * Every channel counts as a peer, so that empty channels that were just placed spread out.
+ * @param {GenericMediaWorker} worker
+ */
+function loadOf(worker) {
+    let channels = 0;
+    for (const channel of activeChannels.values()) {
+        if (channel.workerIndex === worker.index) {
+            channels++;
+        }
+    }
+    return Math.max(worker.load?.peers ?? 0, peersOnWorker(worker)) + channels;
+}
+
 /**
  * @param {number} id
  * @param {string} code
  */
 function controllerURL(id, code) {
     if (config.unixSocket) {
-        return `ws://unix/media-worker/${id}/${code}:${config.unixSocket}`;
+        return `ws+unix://${config.unixSocket}:/media-worker/${id}/${code}`;
     }
     if (config.httpPort) {
         return `ws://localhost:${config.httpPort}/media-worker/${id}/${code}`;
@@ -180,15 +520,28 @@ function controllerURL(id, code) {
     throw "config error, http must be configured for the SFU";
 }
 
-let msWorkerIndex = 0;
-
 /**
+ * Picks the least loaded worker that can take another channel, CPU breaking ties.
  * @returns {GenericMediaWorker}
  */
 function getMediaWorker() {
-    const mediaWorker = /** @type {GenericMediaWorker} */ (
-        mediaWorkers[msWorkerIndex++ % mediaWorkers.length]
-    );
+    /** @type {GenericMediaWorker | undefined} */
+    let mediaWorker = undefined;
+    let bestLoad = Infinity;
+    for (const worker of mediaWorkers) {
+        if (!canPlaceOn(worker)) {
+            continue;
+        }
+        const load = loadOf(worker);
+        const cpu = worker.load?.cpuPercent ?? 0;
+        if (load < bestLoad || (load === bestLoad && mediaWorker !== undefined && cpu < (mediaWorker.load?.cpuPercent ?? 0))) {
+            mediaWorker = worker;
+            bestLoad = load;
+        }
+    }
+    if (mediaWorker === undefined) {
+        throw "no media worker has room for another channel";
+    }
 
     if (mediaWorker.state === "disconnected") {
         // Just to be sure. And to generate a new code.
@@ -217,6 +570,11 @@ function getMediaWorker() {
                 SFU_LOG_TAGS: config.mediaWorker.worker.logTags.join(";"),
                 SFU_LISTEN_IP: "0.0.0.0", // TODO: make configurable
                 SFU_ANNOUNCE_IP: announceIP,
+                SFU_ICE_SERVERS: JSON.stringify(config.mediaWorker.iceServers),
+                SFU_ON_WORKER_DEATH: config.mediaWorker.worker.onWorkerDeath,
+                SFU_SNAPSHOT_PATH: config.mediaWorker.snapshotDir
+                    ? join(config.mediaWorker.snapshotDir, `media-worker-${mediaWorker.index}.json`)
+                    : "",
             },
             stdio: "inherit",
         });
@@ -228,8 +586,13 @@ function getMediaWorker() {
             resetWorker(mediaWorker);
         });
         cmd.on("exit", function (_) {
+            // Processes that were killed by resetWorker() have already been replaced.
+            if (mediaWorker.process !== cmd) {
+                return;
+            }
             error("media worker at index", mediaWorker.index, "exited unexpectedly");
             resetWorker(mediaWorker);
+            replaceChannelsOf(mediaWorker);
         });
     }
 
@@ -307,6 +670,96 @@ export function sendMessageSFU(voiceState, message) {
     }
 }
 
+/**
+ * @typedef {{
+ *  ssrc: number,
+ *  payloadType?: number,
+ *  remote?: string,
+ *  comedia?: boolean,
+ * }} RtpEndpoint
+ */
+
+/**
+ * @typedef {NonNullable<MessageFromSFU["rtpPeerCreated"]>} RtpPeerCreated
+ */
+
+/**
+ * Dial-in peers waiting for their media worker to create them, keyed by channel and peer ID.
+ * @type {Map<string, { resolve: (created: RtpPeerCreated) => void, timeout: ReturnType<typeof setTimeout> }>}
+ */
+const pendingRtpPeers = new Map();
+
+const RTP_PEER_TIMEOUT_MS = 10000;
+
+/**
+ * Adds a dial-in peer to an active channel, for a SIP gateway bridging a call. Resolves with
+ * the tuple the gateway should send its RTP to, once the media worker has created the peer.
+ * @param {number} channelID
+ * @param {number} peerID
+ * @param {RtpEndpoint} endpoint
+ * @returns {Promise<RtpPeerCreated>}
+ */
+export function addRtpPeer(channelID, peerID, endpoint) {
+    const activeChannel = activeChannels.get(channelID);
+    if (activeChannel === undefined) {
+        return Promise.reject("channel is not active");
+    }
+    const key = `${channelID}:${peerID}`;
+    if (pendingRtpPeers.has(key)) {
+        return Promise.reject("RTP peer is already being added");
+    }
+    return new Promise(function (resolve, reject) {
+        const timeout = setTimeout(function () {
+            pendingRtpPeers.delete(key);
+            reject("media worker did not create the RTP peer");
+        }, RTP_PEER_TIMEOUT_MS);
+        pendingRtpPeers.set(key, { resolve, timeout });
+        sendMediaMessage(activeChannel.workerIndex, {
+            type: "AddRtpPeer",
+            channel: channelID,
+            peer: peerID,
+            ssrc: endpoint.ssrc,
+            payload_type: endpoint.payloadType,
+            remote: endpoint.remote,
+            comedia: endpoint.comedia ?? false,
+        });
+    });
+}
+
+/**
+ * @param {number} channelID
+ * @param {number} peerID
+ */
+export function removeRtpPeer(channelID, peerID) {
+    const activeChannel = activeChannels.get(channelID);
+    if (activeChannel !== undefined) {
+        sendMediaMessage(activeChannel.workerIndex, { type: "RemovePeer", channel: channelID, peer: peerID });
+    }
+}
+
+/**
+ * Streams the mixed audio of a channel over HTTP at the given address, such as "0.0.0.0:8000".
+ * @param {number} channelID
+ * @param {string} address
+ */
+export function startBroadcast(channelID, address) {
+    const activeChannel = activeChannels.get(channelID);
+    if (activeChannel === undefined) {
+        throw "channel is not active";
+    }
+    sendMediaMessage(activeChannel.workerIndex, { type: "StartBroadcast", channel: channelID, address });
+}
+
+/**
+ * @param {number} channelID
+ */
+export function stopBroadcast(channelID) {
+    const activeChannel = activeChannels.get(channelID);
+    if (activeChannel !== undefined) {
+        sendMediaMessage(activeChannel.workerIndex, { type: "StopBroadcast", channel: channelID });
+    }
+}
+
 /**
  * Synchronizes VoiceState with both clients and media workers.
  * @param {VoiceState} voiceState
@@ -85,6 +85,17 @@ const mediaCodecs = z.array(
     })
 );
 
+const mediaWorkerIceServers = z.array(
+    z.object({
+        urls: z.array(z.string()),
+        username: z.string().optional(),
+        credential: z.string().optional(),
+        // Shared with the TURN server, used to generate short-lived credentials.
+        secret: z.string().optional(),
+        ttl: z.number().int().gt(0).optional(),
+    })
+);
+
 /** @type {z.infer<typeof mediaCodecs>} */
 const reasonableMediaCodecs = [
     {
@@ -209,7 +220,16 @@ const config = {
             rtcMaxPort: configNumber("MEDIA_WORKER_RTC_MAX_PORT", 59999),
             logLevel: configString("MEDIA_WORKER_LOG_LEVEL", "warn"),
             logTags: configStringArray("MEDIA_WORKER_LOG_TAGS"),
+            // "exit" or "recreate", see WorkerDeathPolicy in the SFU.
+            onWorkerDeath: configString("MEDIA_WORKER_ON_WORKER_DEATH", "exit"),
+            // Warn once a worker reports fewer free RTC ports than this.
+            freePortsWarning: configNumber("MEDIA_WORKER_FREE_PORTS_WARNING", 500),
         },
+        iceServers: configJson(mediaWorkerIceServers, "MEDIA_WORKER_ICE_SERVERS", []),
+        // Directory where each media worker keeps a snapshot of its state. Empty disables snapshots.
+        snapshotDir: configString("MEDIA_WORKER_SNAPSHOT_DIR", ""),
+        // Shared with media workers started elsewhere with SFU_REMOTE_TOKEN. Empty disables remote workers.
+        remoteToken: configString("MEDIA_WORKER_REMOTE_TOKEN", ""),
         router: {
             // TODO: mediaCodecs are not even sent, should we remove the code for json parsing?
             mediaCodecs: configJson(mediaCodecs, "MEDIA_WORKER_CODECS", reasonableMediaCodecs),
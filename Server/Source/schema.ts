@@ -165,14 +165,16 @@ const rtpParameters = z.object({
         .optional(),
 });
 
+const iceParameters = z.object({
+    usernameFragment: z.string(),
+    password: z.string(),
+    iceLite: z.oboolean(),
+});
+
 // TransportOptions
 const transportOptions = z.object({
     id: z.string(),
-    iceParameters: z.object({
-        usernameFragment: z.string(),
-        password: z.string(),
-        iceLite: z.oboolean(),
-    }),
+    iceParameters,
     iceCandidates: z.array(
         z.object({
             foundation: z.string(),
@@ -210,6 +212,54 @@ const transportOptions = z.object({
     appData: z.record(z.any()).optional(), // TODO: explore possible values
 });
 
+// Passed on by the SFU as is, such as { label, source, muted }.
+const producerAppData = z.record(z.any());
+
+const sfuSnapshot = z.object({
+    takenAt: z.number(),
+    channels: z.array(
+        z.object({
+            channel: z.number(),
+            // Set for breakout rooms, which the controller never placed.
+            parent: z.number().nullable().optional(),
+            codecs: z.array(z.any()),
+            // In bits per second, null where there is no cap.
+            bitrate: z.object({
+                maxIncomingBitrate: z.number().nullable(),
+                maxOutgoingBitrate: z.number().nullable(),
+                initialAvailableOutgoingBitrate: z.number().nullable(),
+            }).optional(),
+            lastN: z.number().nullable(),
+            peers: z.array(
+                z.object({
+                    peerID: z.number(),
+                    deaf: z.boolean(),
+                    role: z.enum(["speaker", "listener"]),
+                    handRaised: z.boolean(),
+                    priority: z.boolean(),
+                    rtp: z.boolean(),
+                    producers: z.array(
+                        z.object({
+                            id: z.string(),
+                            kind: z.enum(["audio", "video"]),
+                            paused: z.boolean(),
+                            audience: z.array(z.number()).nullable(),
+                        })
+                    ),
+                    consumers: z.array(
+                        z.object({
+                            id: z.string(),
+                            producerID: z.string(),
+                            paused: z.boolean(),
+                        })
+                    ).optional(),
+                })
+            ),
+        })
+    ),
+});
+export type SFUSnapshot = z.infer<typeof sfuSnapshot>;
+
 export const messageFromSFU = z.object({
     capabilities: rtpCapabilities.optional(),
     newProducers: z
@@ -217,10 +267,79 @@ export const messageFromSFU = z.object({
             z.object({
                 peerID: z.number(),
                 producerID: z.string(),
+                appData: producerAppData.optional(),
             })
         )
         .optional(),
+    producerUpdated: z
+        .object({
+            peerID: z.number(),
+            producerID: z.string(),
+            appData: producerAppData.optional(),
+        })
+        .optional(),
     consumerClosed: z.ostring(),
+    producerClosed: z
+        .object({
+            peerID: z.number(),
+            producerID: z.string(),
+        })
+        .optional(),
+    peerLeft: z.onumber(),
+    moved: z
+        .object({
+            channel: z.number(),
+            capabilities: rtpCapabilities,
+        })
+        .optional(),
+    speakers: z.array(z.number()).optional(),
+    forwardedSpeakers: z.array(z.number()).nullable().optional(),
+    duck: z
+        .object({
+            factor: z.number(),
+            speakers: z.array(z.number()),
+        })
+        .optional(),
+    breakoutStarted: z
+        .object({
+            rooms: z.array(
+                z.object({
+                    room: z.number(),
+                    peers: z.array(z.number()),
+                })
+            ),
+            seconds: z.number().nullable(),
+        })
+        .optional(),
+    breakoutAssigned: z
+        .object({
+            room: z.number().nullable(),
+        })
+        .optional(),
+    returnToMain: z
+        .object({
+            seconds: z.number(),
+        })
+        .optional(),
+    breakoutEnded: z.object({}).optional(),
+    handRaised: z
+        .object({
+            peerID: z.number(),
+            raised: z.boolean(),
+        })
+        .optional(),
+    producerTrace: z
+        .object({
+            peerID: z.number(),
+            producerID: z.string(),
+            event: z.object({
+                type: z.enum(["rtp", "keyframe", "nack", "pli", "fir"]),
+                timestamp: z.number(),
+                direction: z.enum(["in", "out"]),
+                ssrc: z.number().nullable(),
+            }),
+        })
+        .optional(),
     transportCreated: z
         .object({
             errand: z.number(),
@@ -232,6 +351,24 @@ export const messageFromSFU = z.object({
             errand: z.number(),
         })
         .optional(),
+    iceRestarted: z
+        .object({
+            errand: z.number(),
+            iceParameters,
+        })
+        .optional(),
+    iceStateChanged: z
+        .object({
+            transportID: z.string(),
+            state: z.enum(["new", "connected", "completed", "disconnected", "closed"]),
+        })
+        .optional(),
+    iceSelectedTupleChanged: z
+        .object({
+            transportID: z.string(),
+            tuple: z.record(z.any()),
+        })
+        .optional(),
     transportProducing: z
         .object({
             errand: z.number(),
@@ -246,7 +383,56 @@ export const messageFromSFU = z.object({
             rtpParameters,
         })
         .optional(),
+    broadcastStatus: z
+        .object({
+            live: z.boolean(),
+            address: z.string().nullable(),
+            listeners: z.number(),
+        })
+        .optional(),
+    rtpPeerCreated: z
+        .object({
+            peerID: z.number(),
+            tuple: z.record(z.any()),
+            rtpParameters,
+        })
+        .optional(),
+    snapshot: sfuSnapshot.optional(),
+    previousSnapshot: sfuSnapshot.optional(),
+    loadReport: z
+        .object({
+            channels: z.number(),
+            peers: z.number(),
+            producers: z.number(),
+            consumers: z.number(),
+            cpuPercent: z.number(),
+            freeRtcPorts: z.number(),
+        })
+        .optional(),
+    workerDied: z
+        .object({
+            reason: z.string(),
+            channels: z.array(z.number()),
+            recreated: z.boolean(),
+        })
+        .optional(),
+    mixedAudio: z
+        .object({
+            id: z.string(),
+            producerID: z.string(),
+            rtpParameters,
+        })
+        .optional(),
 });
+export const mediaWorkerRegistration = z.object({
+    register: z.object({
+        token: z.string(),
+        region: z.string(),
+        capacity: z.number(),
+        announceIPs: z.array(z.string()),
+    }),
+});
+
 export const sfuToServer = z.tuple([z.number(), z.number(), messageFromSFU]);
 
 export type MessageFromSFU = z.infer<typeof messageFromSFU>;
@@ -271,14 +457,35 @@ export const messageToSFU = z.object({
             producerTransportID: z.string(),
             kind: mediaKind,
             rtpParameters,
+            // Peers to whisper to, instead of the whole channel.
+            audience: z.array(z.number()).optional(),
+            appData: producerAppData.optional(),
             errand: z.number(),
         })
         .optional(),
+    setProducerAudience: z
+        .object({
+            producerID: z.string(),
+            audience: z.array(z.number()).optional(),
+        })
+        .optional(),
+    setProducerAppData: z
+        .object({
+            producerID: z.string(),
+            appData: producerAppData,
+        })
+        .optional(),
     producerClosed: z
         .object({
             producerID: z.string(),
         })
         .optional(),
+    restartIce: z
+        .object({
+            transportID: z.string(),
+            errand: z.number(),
+        })
+        .optional(),
     consumeProducer: z
         .object({
             rtpCapabilities,
@@ -286,7 +493,24 @@ export const messageToSFU = z.object({
             producerID: z.string(),
         })
         .optional(),
-    getProducers: z.object({}).optional(),
+    getProducers: z
+        .object({
+            // Only producers of these kinds, instead of all of them.
+            kinds: z.array(mediaKind).optional(),
+        })
+        .optional(),
+    raiseHand: z
+        .object({
+            raised: z.boolean(),
+        })
+        .optional(),
+    requestMixedAudio: z
+        .object({
+            rtpCapabilities,
+            consumerTransportID: z.string(),
+        })
+        .optional(),
+    stopMixedAudio: z.object({}).optional(),
 });
 
 export type MessageToSFU = z.infer<typeof messageToSFU>;
@@ -19,6 +19,7 @@ import {
     deleteChannel,
     joinChannel,
     mediaWorkerWebSocketConnected,
+    mediaWorkerRegistering,
     sendMessageSFU,
     setChannelName,
     setClientMessageCallback,
@@ -963,6 +964,11 @@ function commonWebSocketResponder(ws, req) {
         return;
     }
 
+    if (parts[2] === "register") {
+        mediaWorkerRegistering(ws);
+        return;
+    }
+
     mediaWorkerWebSocketConnected(ws, parseInt(parts[2] || ""), parts[3] || "");
 }
 